@@ -0,0 +1,31 @@
+//! Runtime API for querying positions of the perpetualasset module
+
+#![cfg_attr(not(feature = "std"), no_std)]
+// The `too_many_arguments` warning originates from `decl_runtime_apis` macro.
+#![allow(clippy::too_many_arguments)]
+// The `unnecessary_mut_passed` warning originates from `decl_runtime_apis` macro.
+#![allow(clippy::unnecessary_mut_passed)]
+
+use codec::Codec;
+use module_perpetualasset::{MarketId, MintSimulation, PositionInfo};
+use primitives::{Amount, Balance};
+use support::Price;
+
+sp_api::decl_runtime_apis! {
+	pub trait PerpetualAssetApi<AccountId> where
+		AccountId: Codec,
+	{
+		/// Gets the position of `account` in `market` at the last price
+		fn position(market: MarketId, account: AccountId) -> PositionInfo;
+		/// Gets the margin of `account` in `market` over the value of its position
+		fn margin_ratio(market: MarketId, account: AccountId) -> Option<Price>;
+		/// Gets the price at which the inventory of `account` in `market` would be liquidated
+		fn liquidation_price(market: MarketId, account: AccountId) -> Option<Price>;
+		/// Gets the largest quantity `account` can add to its position in `market` with `collateral`
+		fn max_mintable(market: MarketId, account: AccountId, collateral: Amount) -> Option<Balance>;
+		/// Gets the margin `account` needs in `market` for its position to change by `delta`
+		fn required_margin(market: MarketId, account: AccountId, delta: Amount) -> Option<Balance>;
+		/// Simulates `account` minting `amount` in `market` with `collateral`
+		fn simulate_mint(market: MarketId, account: AccountId, amount: Amount, collateral: Amount) -> MintSimulation;
+	}
+}
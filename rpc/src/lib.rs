@@ -0,0 +1,173 @@
+//! RPC interface for querying positions of the perpetualasset module
+
+use std::sync::Arc;
+
+use codec::Codec;
+use jsonrpc_core::{Error as RpcError, ErrorCode, Result};
+use jsonrpc_derive::rpc;
+use module_perpetualasset::{MarketId, MintSimulation, PositionInfo};
+use primitives::Amount;
+use sp_api::ProvideRuntimeApi;
+use sp_blockchain::HeaderBackend;
+use sp_rpc::number::NumberOrHex;
+use sp_runtime::{generic::BlockId, traits::Block as BlockT};
+use support::Price;
+
+pub use module_perpetualasset_rpc_runtime_api::PerpetualAssetApi as PerpetualAssetRuntimeApi;
+
+/// Balances are returned as `NumberOrHex`, and the amounts of `PositionInfo` and `MintSimulation`
+/// as strings, JSON numbers not being exact beyond $2^{53}$
+#[rpc]
+pub trait PerpetualAssetApi<BlockHash, AccountId> {
+	#[rpc(name = "perpetualAsset_position")]
+	fn position(&self, market: MarketId, account: AccountId, at: Option<BlockHash>) -> Result<PositionInfo>;
+
+	#[rpc(name = "perpetualAsset_marginRatio")]
+	fn margin_ratio(&self, market: MarketId, account: AccountId, at: Option<BlockHash>) -> Result<Option<Price>>;
+
+	#[rpc(name = "perpetualAsset_liquidationPrice")]
+	fn liquidation_price(&self, market: MarketId, account: AccountId, at: Option<BlockHash>) -> Result<Option<Price>>;
+
+	#[rpc(name = "perpetualAsset_maxMintable")]
+	fn max_mintable(
+		&self,
+		market: MarketId,
+		account: AccountId,
+		collateral: Amount,
+		at: Option<BlockHash>,
+	) -> Result<Option<NumberOrHex>>;
+
+	#[rpc(name = "perpetualAsset_requiredMargin")]
+	fn required_margin(
+		&self,
+		market: MarketId,
+		account: AccountId,
+		delta: Amount,
+		at: Option<BlockHash>,
+	) -> Result<Option<NumberOrHex>>;
+
+	#[rpc(name = "perpetualAsset_simulateMint")]
+	fn simulate_mint(
+		&self,
+		market: MarketId,
+		account: AccountId,
+		amount: Amount,
+		collateral: Amount,
+		at: Option<BlockHash>,
+	) -> Result<MintSimulation>;
+}
+
+/// A struct that implements the [`PerpetualAssetApi`].
+pub struct PerpetualAsset<C, B> {
+	client: Arc<C>,
+	_marker: std::marker::PhantomData<B>,
+}
+
+impl<C, B> PerpetualAsset<C, B> {
+	/// Create new `PerpetualAsset` with the given reference to the client.
+	pub fn new(client: Arc<C>) -> Self {
+		Self {
+			client,
+			_marker: Default::default(),
+		}
+	}
+}
+
+/// Error type of this RPC api.
+pub enum Error {
+	/// The call to runtime failed.
+	RuntimeError,
+}
+
+impl From<Error> for i64 {
+	fn from(e: Error) -> i64 {
+		match e {
+			Error::RuntimeError => 1,
+		}
+	}
+}
+
+fn runtime_error(e: impl std::fmt::Debug) -> RpcError {
+	RpcError {
+		code: ErrorCode::ServerError(Error::RuntimeError.into()),
+		message: "Unable to query the perpetual asset position.".into(),
+		data: Some(format!("{:?}", e).into()),
+	}
+}
+
+impl<C, Block, AccountId> PerpetualAssetApi<<Block as BlockT>::Hash, AccountId> for PerpetualAsset<C, Block>
+where
+	Block: BlockT,
+	C: Send + Sync + 'static + ProvideRuntimeApi<Block> + HeaderBackend<Block>,
+	C::Api: PerpetualAssetRuntimeApi<Block, AccountId>,
+	AccountId: Codec,
+{
+	fn position(&self, market: MarketId, account: AccountId, at: Option<<Block as BlockT>::Hash>) -> Result<PositionInfo> {
+		let api = self.client.runtime_api();
+		let at = BlockId::hash(at.unwrap_or_else(|| self.client.info().best_hash));
+		api.position(&at, market, account).map_err(runtime_error)
+	}
+
+	fn margin_ratio(
+		&self,
+		market: MarketId,
+		account: AccountId,
+		at: Option<<Block as BlockT>::Hash>,
+	) -> Result<Option<Price>> {
+		let api = self.client.runtime_api();
+		let at = BlockId::hash(at.unwrap_or_else(|| self.client.info().best_hash));
+		api.margin_ratio(&at, market, account).map_err(runtime_error)
+	}
+
+	fn liquidation_price(
+		&self,
+		market: MarketId,
+		account: AccountId,
+		at: Option<<Block as BlockT>::Hash>,
+	) -> Result<Option<Price>> {
+		let api = self.client.runtime_api();
+		let at = BlockId::hash(at.unwrap_or_else(|| self.client.info().best_hash));
+		api.liquidation_price(&at, market, account).map_err(runtime_error)
+	}
+
+	fn max_mintable(
+		&self,
+		market: MarketId,
+		account: AccountId,
+		collateral: Amount,
+		at: Option<<Block as BlockT>::Hash>,
+	) -> Result<Option<NumberOrHex>> {
+		let api = self.client.runtime_api();
+		let at = BlockId::hash(at.unwrap_or_else(|| self.client.info().best_hash));
+		api.max_mintable(&at, market, account, collateral)
+			.map(|mintable| mintable.map(NumberOrHex::from))
+			.map_err(runtime_error)
+	}
+
+	fn required_margin(
+		&self,
+		market: MarketId,
+		account: AccountId,
+		delta: Amount,
+		at: Option<<Block as BlockT>::Hash>,
+	) -> Result<Option<NumberOrHex>> {
+		let api = self.client.runtime_api();
+		let at = BlockId::hash(at.unwrap_or_else(|| self.client.info().best_hash));
+		api.required_margin(&at, market, account, delta)
+			.map(|margin| margin.map(NumberOrHex::from))
+			.map_err(runtime_error)
+	}
+
+	fn simulate_mint(
+		&self,
+		market: MarketId,
+		account: AccountId,
+		amount: Amount,
+		collateral: Amount,
+		at: Option<<Block as BlockT>::Hash>,
+	) -> Result<MintSimulation> {
+		let api = self.client.runtime_api();
+		let at = BlockId::hash(at.unwrap_or_else(|| self.client.info().best_hash));
+		api.simulate_mint(&at, market, account, amount, collateral).map_err(runtime_error)
+	}
+}
@@ -0,0 +1,247 @@
+// Copyright (C) 2021 Georges Dib.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Benchmarks for perpetualasset module.
+
+#![cfg(feature = "runtime-benchmarks")]
+
+use super::*;
+use frame_benchmarking::{account, benchmarks, impl_benchmark_test_suite, whitelisted_caller};
+use frame_support::traits::UnfilteredDispatchable;
+use frame_system::RawOrigin;
+use sp_runtime::traits::One;
+
+const SEED: u32 = 0;
+const ENDOWMENT: Balance = 1_000_000_000_000_000_000;
+
+/// Lists a market with a fee matched as per `matching_policy`, at a price of 1
+fn create_market<T: Config>(matching_policy: MatchingPolicy) -> MarketId {
+	let market = Pallet::<T>::do_create_market(MarketInfo {
+		currency_id: T::NativeCurrencyId::get(),
+		initial_im_ratio: Permill::from_percent(20),
+		liquidation_ratio: Permill::from_percent(10),
+		transaction_fee: Permill::from_parts(3000),
+		liquidation_penalty: Permill::from_percent(10),
+		liquidation_step: Permill::one(),
+		matching_policy,
+	});
+	Price0::<T>::insert(market, Price::one());
+	market
+}
+
+fn funded_account<T: Config>(name: &'static str, index: u32) -> T::AccountId {
+	let who: T::AccountId = account(name, index, SEED);
+	T::Currency::deposit(T::NativeCurrencyId::get(), &who, ENDOWMENT).unwrap();
+	who
+}
+
+/// Opens `n` positions of 100 in `market`, longs and shorts in turn, the longs with just enough margin
+fn open_positions<T: Config>(market: MarketId, n: u32) -> Vec<T::AccountId> {
+	(0..n).map(|i| {
+		let who = funded_account::<T>("trader", i);
+		let (amount, collateral) = if i % 2 == 0 { (100, 21) } else { (-100, 1_000) };
+		Pallet::<T>::do_mint(who.clone(), market, amount, collateral).unwrap();
+		who
+	}).collect()
+}
+
+benchmarks! {
+	create_market {
+		let origin = T::UpdateOrigin::successful_origin();
+		let call = Call::<T>::create_market(
+			T::NativeCurrencyId::get(),
+			Permill::from_percent(20),
+			Permill::from_percent(10),
+			Permill::from_parts(3000),
+			Permill::from_percent(10),
+			Permill::one(),
+			MatchingPolicy::ProRata,
+		);
+	}: { call.dispatch_bypass_filter(origin)? }
+	verify {
+		assert!(Pallet::<T>::markets(Pallet::<T>::next_market_id() - 1).is_some());
+	}
+
+	set_market_parameter {
+		let market = create_market::<T>(MatchingPolicy::ProRata);
+		let origin = T::UpdateOrigin::successful_origin();
+		let call = Call::<T>::set_initial_im_ratio(market, Permill::from_percent(30));
+	}: { call.dispatch_bypass_filter(origin)? }
+	verify {
+		assert_eq!(Pallet::<T>::markets(market).unwrap().initial_im_ratio, Permill::from_percent(30));
+	}
+
+	// Opens the n-th position of the market
+	mint_or_burn {
+		let n in 1 .. T::MaxPositions::get();
+		let market = create_market::<T>(MatchingPolicy::ProRata);
+		open_positions::<T>(market, n - 1);
+		let caller: T::AccountId = whitelisted_caller();
+		T::Currency::deposit(T::NativeCurrencyId::get(), &caller, ENDOWMENT)?;
+	}: _(RawOrigin::Signed(caller.clone()), market, 100, true, 1_000, true)
+	verify {
+		assert_eq!(Pallet::<T>::balances(market, &caller), 100);
+	}
+
+	deposit_margin {
+		let market = create_market::<T>(MatchingPolicy::ProRata);
+		let caller: T::AccountId = whitelisted_caller();
+		T::Currency::deposit(T::NativeCurrencyId::get(), &caller, ENDOWMENT)?;
+	}: _(RawOrigin::Signed(caller.clone()), market, 1_000)
+	verify {
+		assert_eq!(Pallet::<T>::margin(market, &caller), 1_000);
+	}
+
+	withdraw_margin {
+		let n in 1 .. T::MaxPositions::get();
+		let market = create_market::<T>(MatchingPolicy::ProRata);
+		open_positions::<T>(market, n - 1);
+		let caller: T::AccountId = whitelisted_caller();
+		T::Currency::deposit(T::NativeCurrencyId::get(), &caller, ENDOWMENT)?;
+		Pallet::<T>::do_mint(caller.clone(), market, 100, 1_000).map_err(|e| e.error)?;
+	}: _(RawOrigin::Signed(caller.clone()), market, 500)
+	verify {
+		assert_eq!(Pallet::<T>::margin(market, &caller), 499);
+	}
+
+	// Matched by priority, the inventory closed is rebalanced against the other side
+	close_position {
+		let n in 2 .. T::MaxPositions::get();
+		let market = create_market::<T>(MatchingPolicy::Fifo);
+		let accounts = open_positions::<T>(market, n);
+		Pallet::<T>::match_interest(market);
+	}: _(RawOrigin::Signed(accounts[0].clone()), market)
+	verify {
+		assert_eq!(Pallet::<T>::margin(market, &accounts[0]), 0);
+		assert!(Pallet::<T>::do_try_state().is_ok());
+	}
+
+	place_order {
+		let market = create_market::<T>(MatchingPolicy::ProRata);
+		let caller: T::AccountId = whitelisted_caller();
+		T::Currency::deposit(T::NativeCurrencyId::get(), &caller, ENDOWMENT)?;
+	}: _(RawOrigin::Signed(caller), market, Side::Long, 100, Price::one(), 10u32.into())
+	verify {
+		assert!(Pallet::<T>::orders(market, 0).is_some());
+	}
+
+	cancel_order {
+		let market = create_market::<T>(MatchingPolicy::ProRata);
+		let caller: T::AccountId = whitelisted_caller();
+		T::Currency::deposit(T::NativeCurrencyId::get(), &caller, ENDOWMENT)?;
+		Pallet::<T>::place_order(
+			RawOrigin::Signed(caller.clone()).into(), market, Side::Long, 100, Price::one(), 10u32.into()
+		).map_err(|e| e.error)?;
+	}: _(RawOrigin::Signed(caller), market, 0)
+	verify {
+		assert!(Pallet::<T>::orders(market, 0).is_none());
+	}
+
+	// Matched by priority, the inventory liquidated is rebalanced against the other side
+	liquidate {
+		let n in 2 .. T::MaxPositions::get();
+		let market = create_market::<T>(MatchingPolicy::Fifo);
+		let accounts = open_positions::<T>(market, n);
+		Pallet::<T>::match_interest(market);
+		Pallet::<T>::mark_to_market(market, Price::saturating_from_rational(85, 100));
+		let caller: T::AccountId = whitelisted_caller();
+	}: _(RawOrigin::Signed(caller), market, accounts[0].clone())
+	verify {
+		assert_eq!(Pallet::<T>::inventory(market, &accounts[0]), 0);
+	}
+
+	// Independent of the number of accounts, which only realize the move when settled
+	update_margin {
+		let market = create_market::<T>(MatchingPolicy::ProRata);
+		open_positions::<T>(market, 2);
+		Pallet::<T>::match_interest(market);
+	}: {
+		Pallet::<T>::mark_to_market(market, Price::saturating_from_integer(20));
+	}
+	verify {
+		assert!(!Pallet::<T>::cumulative_pnl(market).0.is_zero());
+	}
+
+	accrue_funding {
+		let market = create_market::<T>(MatchingPolicy::ProRata);
+		open_positions::<T>(market, 3);
+	}: {
+		Pallet::<T>::accrue_funding(market);
+	}
+	verify {
+		assert!(!Pallet::<T>::cumulative_funding(market).0.is_zero());
+	}
+
+	// Half the accounts checked, the longs, are liquidated
+	liquidate_accounts {
+		let n in 2 .. T::MaxLiquidationsPerBlock::get().max(2);
+		let market = create_market::<T>(MatchingPolicy::ProRata);
+		open_positions::<T>(market, n);
+		Pallet::<T>::match_interest(market);
+		Pallet::<T>::mark_to_market(market, Price::saturating_from_rational(85, 100));
+	}: {
+		Pallet::<T>::liquidate_accounts(market);
+	}
+
+	// Every order is filled
+	fill_orders {
+		let n in 1 .. T::MaxOrderFillsPerBlock::get().min(T::MaxOrders::get()).max(1);
+		let market = create_market::<T>(MatchingPolicy::ProRata);
+		for i in 0 .. n {
+			let who = funded_account::<T>("trader", i);
+			Pallet::<T>::do_mint(who.clone(), market, 0, 1_000).map_err(|e| e.error)?;
+			Pallet::<T>::place_order(
+				RawOrigin::Signed(who).into(), market, Side::Long, 100, Price::one(), 10u32.into()
+			).map_err(|e| e.error)?;
+		}
+	}: {
+		Pallet::<T>::fill_orders(market);
+	}
+	verify {
+		assert_eq!(Pallet::<T>::total_interest(market), (100 * n as Balance, 0));
+	}
+
+	// The longs left unmatched take over part of the inventory closed, the shorts lose the rest
+	rebalance_inventory {
+		let n in 2 .. T::MaxPositions::get();
+		let market = create_market::<T>(MatchingPolicy::Fifo);
+		let accounts = open_positions::<T>(market, n);
+		Pallet::<T>::match_interest(market);
+		let closed = Pallet::<T>::inventory(market, &accounts[0]);
+		Pallet::<T>::set_balance(market, &accounts[0], 0);
+		Pallet::<T>::write_inventory(market, &accounts[0], 0);
+	}: {
+		Pallet::<T>::rebalance_inventory(market, &accounts[0], closed as Balance, true);
+	}
+	verify {
+		assert!(Pallet::<T>::do_try_state().is_ok());
+	}
+
+	// Every account has interest to match, by priority which reads them all
+	match_interest {
+		let n in 2 .. T::MaxPositions::get();
+		let market = create_market::<T>(MatchingPolicy::Fifo);
+		open_positions::<T>(market, n);
+	}: {
+		Pallet::<T>::match_interest(market);
+	}
+}
+
+impl_benchmark_test_suite!(
+	Pallet,
+	crate::mock::ExtBuilder::default().build(),
+	crate::mock::Runtime,
+);
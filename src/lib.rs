@@ -20,71 +20,242 @@
 //!
 //! Given an asset for which an Oracle can provide a price, give a way
 //! for longs and shorts to express their view
+//!
+//! Each perpetual is listed as a market, identified by a `MarketId`, with its own
+//! underlying currency and risk parameters. Positions and margins are isolated per market.
 
-// TODO: add weight stuff, and benchmark it
 // TODO: allow any sort of payoff
 // TODO: make documentation better
 // TODO: clean up code
-// TODO: check collateral redeeming cases, for now if pool is at a loss
-//       there is a race, and the first person to claim collateral takes
-//       more than the others (the others may end up with 0!)
-// TODO: Should I clean 0 balances to clear up storage?
-// TODO: move liquidation and all this to offchain worker
 
 #![cfg_attr(not(feature = "std"), no_std)]
 #![allow(clippy::unused_unit)]
 
 use frame_support::{pallet_prelude::*, PalletId, transactional,
-	traits::{OnUnbalanced, Currency, ReservableCurrency}};
-use frame_system::pallet_prelude::*;
+	traits::{OnUnbalanced, Currency, ReservableCurrency, WithdrawReasons, ExistenceRequirement}};
+use frame_system::{pallet_prelude::*, offchain::{SendTransactionTypes, SubmitTransaction}};
 
 use orml_traits::{MultiCurrency, MultiCurrencyExtended};
 use primitives::{Amount, Balance, CurrencyId};
-use sp_runtime::{traits::{AccountIdConversion}, Permill, FixedPointNumber};
-use sp_arithmetic::Perquintill;
-use sp_std::{convert::TryInto, result};
+use frame_support::storage::with_transaction;
+use sp_runtime::{traits::{AccountIdConversion, CheckedDiv, CheckedSub, One, Saturating, SaturatedConversion, Zero},
+	offchain::{StorageKind, storage_lock::{StorageLock, Time}},
+	Permill, FixedPointNumber, FixedI128, DispatchError, DispatchResult, TransactionOutcome};
+use sp_arithmetic::{Perquintill, helpers_128bit::multiply_by_rational};
+use sp_std::{collections::btree_map::BTreeMap, convert::TryInto, result, vec::Vec};
 use support::{Price, PriceProvider};
 
+#[cfg(feature = "std")]
+use serde::{Deserialize, Serialize};
+
+/// Local storage lock held by the offchain worker while it scans the markets
+const OFFCHAIN_LOCK: &[u8] = b"perpetualasset::liquidation-lock";
+/// Local storage prefix of the block at which a liquidation was last submitted for an account
+const OFFCHAIN_SUBMITTED: &[u8] = b"perpetualasset::liquidation-submitted";
+
 mod mock;
 mod tests;
+mod benchmarking;
+pub mod weights;
+
+pub use weights::WeightInfo;
 
 pub use module::*;
 
 type NegativeImbalanceOf<T> =
 	<<T as Config>::FeeCurrency as Currency<<T as frame_system::Config>::AccountId>>::NegativeImbalance;
 
+/// Identifier of a perpetual market
+pub type MarketId = u32;
+
+/// How the open interest of the crowded side is filled by the other side
+#[derive(Encode, Decode, Clone, Copy, RuntimeDebug, PartialEq, Eq)]
+#[cfg_attr(feature = "std", derive(Serialize, Deserialize))]
+pub enum MatchingPolicy {
+	/// Every account is filled in proportion of its interest
+	ProRata,
+	/// Accounts are filled fully in order of submission of their interest
+	Fifo,
+	/// Accounts are filled fully from the largest interest down, in order of submission for equal interests
+	SizeWeighted,
+}
+
+/// Parameters of a perpetual market
+#[derive(Encode, Decode, Clone, Copy, RuntimeDebug, PartialEq, Eq)]
+#[cfg_attr(feature = "std", derive(Serialize, Deserialize))]
+pub struct MarketInfo {
+	/// The asset to be priced
+	pub currency_id: CurrencyId,
+	/// Initial IM Ratio
+	pub initial_im_ratio: Permill,
+	/// Liquidation Ratio
+	pub liquidation_ratio: Permill,
+	/// Transaction fee
+	pub transaction_fee: Permill,
+	/// Share of the remaining margin taken when an inventory is liquidated
+	pub liquidation_penalty: Permill,
+	/// Share of the inventory closed at each step of a liquidation, 0 or 100% close it all at once
+	pub liquidation_step: Permill,
+	/// How interest is matched
+	pub matching_policy: MatchingPolicy,
+}
+
+/// Identifier of an order, unique across markets
+pub type OrderId = u64;
+
+/// Side of an order
+#[derive(Encode, Decode, Clone, Copy, RuntimeDebug, PartialEq, Eq)]
+#[cfg_attr(feature = "std", derive(Serialize, Deserialize))]
+pub enum Side {
+	/// Buys the asset
+	Long,
+	/// Sells the asset
+	Short,
+}
+
+/// An order resting in the order book of a market
+#[derive(Encode, Decode, Clone, RuntimeDebug, PartialEq, Eq)]
+pub struct Order<AccountId, BlockNumber> {
+	/// The account that placed the order
+	pub owner: AccountId,
+	/// Long or short
+	pub side: Side,
+	/// Quantity of asset to add to the interest of `owner` when filled
+	pub quantity: Balance,
+	/// Highest price for a long, lowest price for a short
+	pub limit_price: Price,
+	/// Last block at which the order can be filled
+	pub expiry: BlockNumber,
+	/// Reserved from `owner` while the order rests in the order book
+	pub deposit: Balance,
+}
+
+/// Neighbours of an account in the interest queue of a market
+#[derive(Encode, Decode, Clone, RuntimeDebug, PartialEq, Eq)]
+pub struct QueueLinks<AccountId> {
+	/// Account before it, `None` at the front
+	pub prev: Option<AccountId>,
+	/// Account after it, `None` at the back
+	pub next: Option<AccountId>,
+}
+
+/// Front, back and length of the interest queue of a market
+#[derive(Encode, Decode, Clone, Default, RuntimeDebug, PartialEq, Eq)]
+pub struct QueueEnds<AccountId> {
+	/// First account of the queue
+	pub head: Option<AccountId>,
+	/// Last account of the queue
+	pub tail: Option<AccountId>,
+	/// Number of accounts in the queue
+	pub len: u32,
+}
+
+/// Serde for the amounts of the structs returned over RPC, as strings since JSON numbers
+/// are not exact beyond $2^{53}$
+#[cfg(feature = "std")]
+mod as_string {
+	use serde::{de::Error, Deserialize, Deserializer, Serializer};
+	use std::{fmt::Display, str::FromStr};
+
+	pub fn serialize<S: Serializer, T: Display>(value: &T, serializer: S) -> Result<S::Ok, S::Error> {
+		serializer.serialize_str(&value.to_string())
+	}
+
+	pub fn deserialize<'de, D: Deserializer<'de>, T: FromStr>(deserializer: D) -> Result<T, D::Error> {
+		String::deserialize(deserializer)?.parse().map_err(|_| D::Error::custom("invalid number"))
+	}
+}
+
+/// What made the margin of an account in a market, kept as long as the account has storage in it
+#[derive(Encode, Decode, Clone, Default, RuntimeDebug, PartialEq, Eq)]
+#[cfg_attr(feature = "std", derive(Serialize, Deserialize))]
+pub struct AccountStats {
+	/// Collateral added to the margin
+	#[cfg_attr(feature = "std", serde(with = "as_string"))]
+	pub deposited: Balance,
+	/// Collateral taken out of the margin, before any socialized loss
+	#[cfg_attr(feature = "std", serde(with = "as_string"))]
+	pub withdrawn: Balance,
+	/// PnL of the inventory settled into the margin
+	#[cfg_attr(feature = "std", serde(with = "as_string"))]
+	pub realized_pnl: Amount,
+	/// Transaction fees and liquidation penalties
+	#[cfg_attr(feature = "std", serde(with = "as_string"))]
+	pub fees_paid: Balance,
+	/// Funding settled, negative when received
+	#[cfg_attr(feature = "std", serde(with = "as_string"))]
+	pub funding_paid: Amount,
+}
+
+/// Position of an account in a market, at the last price
+#[derive(Encode, Decode, Clone, RuntimeDebug, PartialEq, Eq)]
+#[cfg_attr(feature = "std", derive(Serialize, Deserialize))]
+pub struct PositionInfo {
+	/// Total interest, negative for a short
+	#[cfg_attr(feature = "std", serde(with = "as_string"))]
+	pub size: Amount,
+	/// Share of `size` matched, negative for a short
+	#[cfg_attr(feature = "std", serde(with = "as_string"))]
+	pub inventory: Amount,
+	/// Share of `size` not matched yet, negative for a short
+	#[cfg_attr(feature = "std", serde(with = "as_string"))]
+	pub open_interest: Amount,
+	/// Average price the inventory was bought or sold at
+	pub entry_price: Option<Price>,
+	/// Margin, unrealized PnL and funding owed included
+	#[cfg_attr(feature = "std", serde(with = "as_string"))]
+	pub margin: Balance,
+	/// PnL of the inventory not settled into the margin yet
+	#[cfg_attr(feature = "std", serde(with = "as_string"))]
+	pub unrealized_pnl: Amount,
+	/// Margin over the value of `size`, `None` without a position or a price
+	pub margin_ratio: Option<Price>,
+}
+
+/// Outcome of a mint, as `mint` would have it at the last price
+#[derive(Encode, Decode, Clone, RuntimeDebug, PartialEq, Eq)]
+#[cfg_attr(feature = "std", derive(Serialize, Deserialize))]
+pub struct MintSimulation {
+	/// Total interest after the mint, negative for a short
+	#[cfg_attr(feature = "std", serde(with = "as_string"))]
+	pub balance: Amount,
+	/// Margin after the collateral is added and the fee taken out, checked against `required_im`
+	#[cfg_attr(feature = "std", serde(with = "as_string"))]
+	pub margin: Amount,
+	/// Transaction fee on the amount minted
+	#[cfg_attr(feature = "std", serde(with = "as_string"))]
+	pub fee: Balance,
+	/// Initial margin needed for `balance`
+	#[cfg_attr(feature = "std", serde(with = "as_string"))]
+	pub required_im: Balance,
+	/// Error `mint` would fail with, `None` if it would go through
+	pub error: Option<DispatchError>,
+}
+
 #[frame_support::pallet]
 pub mod module {
 	use super::*;
 
 	#[pallet::config]
-	pub trait Config: frame_system::Config {
+	pub trait Config: frame_system::Config + SendTransactionTypes<Call<Self>> {
 		type Event: From<Event<Self>> + IsType<<Self as frame_system::Config>::Event>;
 		/// The synthetic's module id, keep all collaterals.
 		#[pallet::constant]
 		type PalletId: Get<PalletId>;
 
-		/// The asset to be priced
+		/// Number of blocks between two funding payments
 		#[pallet::constant]
-		type CurrencyId: Get<CurrencyId>;
+		type FundingInterval: Get<Self::BlockNumber>;
 
-		/// Initial IM Ratio
+		/// Maximum funding rate paid by the crowded side per funding interval
 		#[pallet::constant]
-		type InitialIMRatio: Get<Permill>;
-
-		/// Liquidation Ratio
-		#[pallet::constant]
-		type LiquidationRatio: Get<Permill>;
-
-		/// Transaction fee
-		#[pallet::constant]
-		type TransactionFee: Get<Permill>;
+		type MaxFundingRate: Get<Permill>;
 
 		/// Currency for transfer currencies
 		type Currency: MultiCurrencyExtended<Self::AccountId, CurrencyId = CurrencyId, Balance = Balance>;
 
-		/// The currency type in which fees will be paid.
-		type FeeCurrency: Currency<Self::AccountId> + ReservableCurrency<Self::AccountId>;
+		/// The currency type in which fees will be paid, it must be the currency of `NativeCurrencyId`.
+		type FeeCurrency: Currency<Self::AccountId, Balance = Balance> + ReservableCurrency<Self::AccountId>;
 
 		/// The native currency to pay in.
 		#[pallet::constant]
@@ -95,6 +266,65 @@ pub mod module {
 
 		/// The treasury for funds
 		type Treasury: OnUnbalanced<NegativeImbalanceOf<Self>>;
+
+		/// The origin which may list markets and update their parameters
+		type UpdateOrigin: EnsureOrigin<Self::Origin>;
+
+		/// Share of the transaction fees kept in the insurance fund, the rest goes to the treasury
+		#[pallet::constant]
+		type InsuranceFundShare: Get<Permill>;
+
+		/// Share of the liquidation penalty paid to the liquidator, the rest goes to the insurance fund
+		#[pallet::constant]
+		type LiquidatorShare: Get<Permill>;
+
+		/// Maximum number of accounts checked for liquidation per market and per block,
+		/// 0 leaves liquidations to the offchain worker and to `liquidate`
+		#[pallet::constant]
+		type MaxLiquidationsPerBlock: Get<u32>;
+
+		/// Base priority of the liquidations submitted by the offchain worker
+		#[pallet::constant]
+		type UnsignedPriority: Get<TransactionPriority>;
+
+		/// Number of blocks before the offchain worker submits the liquidation of an account again
+		#[pallet::constant]
+		type UnsignedInterval: Get<Self::BlockNumber>;
+
+		/// Margin below which an account without position is reaped, what is left of its margin
+		/// going to the treasury. At least 1 so that empty accounts are reaped. Deposits leaving
+		/// an account without position below it are refused.
+		#[pallet::constant]
+		type MinimumMargin: Get<Balance>;
+
+		/// Deposit reserved from the owner of an order until it is filled, cancelled or dropped
+		#[pallet::constant]
+		type OrderDeposit: Get<Balance>;
+
+		/// Maximum number of orders resting in a market per account
+		#[pallet::constant]
+		type MaxOrdersPerAccount: Get<u32>;
+
+		/// Maximum number of orders resting in a market, which bounds the order book read every block
+		#[pallet::constant]
+		type MaxOrders: Get<u32>;
+
+		/// Maximum number of orders filled per market and per block, the others waiting for the next block
+		#[pallet::constant]
+		type MaxOrderFillsPerBlock: Get<u32>;
+
+		/// Deposit reserved from an account while it has interest in a market, so that each of the
+		/// `MaxPositions` slots has a cost however small the position
+		#[pallet::constant]
+		type PositionDeposit: Get<Balance>;
+
+		/// Maximum number of accounts with interest in a market, which bounds the work
+		/// of matching, rebalancing and liquidating it
+		#[pallet::constant]
+		type MaxPositions: Get<u32>;
+
+		/// Weight information for the extrinsics and hooks of this module
+		type WeightInfo: WeightInfo;
 	}
 
 	#[pallet::error]
@@ -109,45 +339,263 @@ pub mod module {
 		NotEnoughBalance,
 		/// Emitted when P0 not set
 		PriceNotSet,
+		/// Emitted when the market does not exist
+		MarketNotFound,
+		/// Emitted when the liquidation ratio is not strictly below the initial IM ratio
+		InvalidLiquidationRatio,
+		/// Emitted when trying to liquidate an account with enough margin
+		NotLiquidatable,
+		/// Emitted when the order does not exist
+		OrderNotFound,
+		/// Emitted when cancelling an order of another account
+		NotOrderOwner,
+		/// Emitted when placing an order with no quantity or already expired
+		InvalidOrder,
+		/// Emitted when closing an account with neither position nor margin
+		NothingToClose,
+		/// Emitted when a deposit would leave an account without position below `MinimumMargin`
+		BelowMinimumMargin,
+		/// Emitted when opening interest in a market with `MaxPositions` accounts already
+		TooManyPositions,
+		/// Emitted when placing an order with `MaxOrdersPerAccount` orders resting already
+		TooManyOrders,
+		/// Emitted when placing an order in a market with `MaxOrders` orders resting already
+		OrderBookFull,
 	}
 
 	#[pallet::event]
 	#[pallet::generate_deposit(pub(crate) fn deposit_event)]
 	pub enum Event<T: Config> {
-		/// Emitted when collateral is updated by \[Amount\]
-		CollateralUpdated(Amount),
-		/// Emitted when the balance of \[T::AccountId\] is updated to \[Amount\]
-		BalanceUpdated(T::AccountId, Amount),
+		/// Emitted when market \[MarketId\] is created to price \[CurrencyId\]
+		MarketCreated(MarketId, CurrencyId),
+		/// Emitted when the initial IM ratio of \[MarketId\] is set to \[Permill\]
+		InitialIMRatioUpdated(MarketId, Permill),
+		/// Emitted when the liquidation ratio of \[MarketId\] is set to \[Permill\]
+		LiquidationRatioUpdated(MarketId, Permill),
+		/// Emitted when the transaction fee of \[MarketId\] is set to \[Permill\]
+		TransactionFeeUpdated(MarketId, Permill),
+		/// Emitted when the liquidation penalty of \[MarketId\] is set to \[Permill\]
+		LiquidationPenaltyUpdated(MarketId, Permill),
+		/// Emitted when the liquidation step of \[MarketId\] is set to \[Permill\]
+		LiquidationStepUpdated(MarketId, Permill),
+		/// Emitted when the matching policy of \[MarketId\] is set to \[MatchingPolicy\]
+		MatchingPolicyUpdated(MarketId, MatchingPolicy),
+		/// Emitted when collateral in \[MarketId\] is updated by \[Amount\]
+		CollateralUpdated(MarketId, Amount),
+		/// Emitted when \[T::AccountId\] deposits \[Balance\] of margin in \[MarketId\]
+		MarginDeposited(MarketId, T::AccountId, Balance),
+		/// Emitted when \[T::AccountId\] withdraws \[Balance\] of margin from \[MarketId\]
+		MarginWithdrawn(MarketId, T::AccountId, Balance),
+		/// Emitted when \[T::AccountId\] closes its position of \[Amount\] in \[MarketId\],
+		/// getting back \[Balance\] of margin
+		PositionClosed(MarketId, T::AccountId, Amount, Balance),
+		/// Emitted when \[T::AccountId\] is reaped from \[MarketId\], \[Balance\] of dust margin
+		/// going to the treasury
+		AccountReaped(MarketId, T::AccountId, Balance),
+		/// Emitted when the balance of \[T::AccountId\] in \[MarketId\] is updated to \[Amount\]
+		BalanceUpdated(MarketId, T::AccountId, Amount),
+		/// Emitted when \[T::AccountId\] pays \[Amount\] of funding in \[MarketId\] (negative if received)
+		FundingPaid(MarketId, T::AccountId, Amount),
+		/// Emitted when \[T::AccountId\] is charged a fee of \[Balance\] in \[MarketId\]
+		FeeCharged(MarketId, T::AccountId, Balance),
+		/// Emitted when a withdrawal of \[T::AccountId\] in \[MarketId\] is haircut by \[Balance\]
+		LossSocialized(MarketId, T::AccountId, Balance),
+		/// Emitted when \[Balance\] is paid into the insurance fund from \[MarketId\]
+		InsuranceFundDeposited(MarketId, Balance),
+		/// Emitted when \[Balance\] is taken from the insurance fund to cover losses in \[MarketId\]
+		InsuranceFundWithdrawn(MarketId, Balance),
+		/// Emitted when \[Balance\] of losses in \[MarketId\] are taken from the winning side
+		LossesDeleveraged(MarketId, Balance),
+		/// Emitted when the inventory of \[T::AccountId\] in \[MarketId\] is liquidated with a penalty of \[Balance\]
+		Liquidated(MarketId, T::AccountId, Balance),
+		/// Emitted when \[Amount\] of the inventory of \[T::AccountId\] in \[MarketId\] is closed,
+		/// leaving \[Amount\]
+		PositionLiquidated(MarketId, T::AccountId, Amount, Amount),
+		/// Emitted when \[T::AccountId\] is paid \[Balance\] for a liquidation in \[MarketId\]
+		LiquidatorRewarded(MarketId, T::AccountId, Balance),
+		/// Emitted when \[Amount\] of inventory of \[T::AccountId\] in \[MarketId\] is filled at \[Price\],
+		/// negative for a sale, the entry price of its inventory becoming \[Option<Price>\]
+		InventoryFilled(MarketId, T::AccountId, Amount, Price, Option<Price>),
+		/// Emitted when a liquidated inventory in \[MarketId\] is rebalanced, \[Balance\] being taken over
+		/// by open interest on the same side and \[Balance\] being removed from the opposite side
+		InventoryRebalanced(MarketId, Balance, Balance),
+		/// Emitted when \[T::AccountId\] places order \[OrderId\] in \[MarketId\] to go \[Side\]
+		/// by \[Balance\] at a limit of \[Price\]
+		OrderPlaced(MarketId, OrderId, T::AccountId, Side, Balance, Price),
+		/// Emitted when order \[OrderId\] of \[T::AccountId\] in \[MarketId\] is filled
+		OrderFilled(MarketId, OrderId, T::AccountId),
+		/// Emitted when order \[OrderId\] of \[T::AccountId\] in \[MarketId\] is cancelled,
+		/// expired or cannot be filled any more
+		OrderCancelled(MarketId, OrderId, T::AccountId),
 	}
 
+	#[pallet::storage]
+	#[pallet::getter(fn markets)]
+	pub(crate) type Markets<T: Config> = StorageMap<_, Twox64Concat, MarketId, MarketInfo>;
+
+	#[pallet::storage]
+	#[pallet::getter(fn next_market_id)]
+	pub(crate) type NextMarketId<T: Config> = StorageValue<_, MarketId, ValueQuery>;
+
 	#[pallet::storage]
 	#[pallet::getter(fn balances)]
-	pub(crate) type Balances<T: Config> = StorageMap<_, Twox64Concat, T::AccountId, Amount, ValueQuery>;
+	pub(crate) type Balances<T: Config> =
+		StorageDoubleMap<_, Twox64Concat, MarketId, Twox64Concat, T::AccountId, Amount, ValueQuery>;
+
+	/// Inventory of an account in a market matched by priority. In a `ProRata` market, its interest
+	/// as of the last match instead, of which it holds the share given by `fill_ratios`, see `inventory`.
+	#[pallet::storage]
+	pub(crate) type Inventory<T: Config> =
+		StorageDoubleMap<_, Twox64Concat, MarketId, Twox64Concat, T::AccountId, Amount, ValueQuery>;
+
+	/// Deposit reserved from an account while it has interest in a market
+	#[pallet::storage]
+	#[pallet::getter(fn position_deposit)]
+	pub(crate) type PositionDeposits<T: Config> =
+		StorageDoubleMap<_, Twox64Concat, MarketId, Twox64Concat, T::AccountId, Balance, ValueQuery>;
+
+	/// Margin of an account at its last settlement, see `margin` for its current value
+	#[pallet::storage]
+	pub(crate) type Margin<T: Config> =
+		StorageDoubleMap<_, Twox64Concat, MarketId, Twox64Concat, T::AccountId, Balance, ValueQuery>;
+
+	#[pallet::storage]
+	pub(crate) type Price0<T: Config> = StorageMap<_, Twox64Concat, MarketId, Price>;
+
+	/// Accounts with interest in a market, in order of submission of their interest,
+	/// as a list linking each account to its neighbours
+	#[pallet::storage]
+	pub(crate) type InterestQueue<T: Config> =
+		StorageDoubleMap<_, Twox64Concat, MarketId, Twox64Concat, T::AccountId, QueueLinks<T::AccountId>>;
+
+	/// Ends of `InterestQueue` in a market, and the number of accounts in it
+	#[pallet::storage]
+	pub(crate) type InterestQueueEnds<T: Config> =
+		StorageMap<_, Twox64Concat, MarketId, QueueEnds<T::AccountId>, ValueQuery>;
+
+	/// Total long and short interest in a market
+	#[pallet::storage]
+	#[pallet::getter(fn total_interest)]
+	pub(crate) type TotalInterest<T: Config> = StorageMap<_, Twox64Concat, MarketId, (Balance, Balance), ValueQuery>;
+
+	/// Whether the interest of a market changed since it was last matched
+	#[pallet::storage]
+	pub(crate) type InterestChanged<T: Config> = StorageMap<_, Twox64Concat, MarketId, bool, ValueQuery>;
+
+	/// Accounts whose interest in a market changed since it was last matched
+	#[pallet::storage]
+	pub(crate) type ChangedInterest<T: Config> =
+		StorageDoubleMap<_, Twox64Concat, MarketId, Twox64Concat, T::AccountId, bool, ValueQuery>;
+
+	/// Funding paid so far per unit of long and of short `Inventory`, in native currency
+	#[pallet::storage]
+	#[pallet::getter(fn cumulative_funding)]
+	pub(crate) type CumulativeFunding<T: Config> =
+		StorageMap<_, Twox64Concat, MarketId, (FixedI128, FixedI128), ValueQuery>;
+
+	/// Margin owed to participants across all markets, as per `total_margin`
+	#[pallet::storage]
+	pub(crate) type TotalMargin<T: Config> = StorageValue<_, Balance, ValueQuery>;
+
+	/// Total transaction fees charged, the share kept in the insurance fund included
+	#[pallet::storage]
+	#[pallet::getter(fn total_fees_collected)]
+	pub(crate) type TotalFeesCollected<T: Config> = StorageValue<_, Balance, ValueQuery>;
+
+	/// Margin withdrawn by an account but not paid because the pool was under-water
+	#[pallet::storage]
+	#[pallet::getter(fn socialized_losses)]
+	pub(crate) type SocializedLosses<T: Config> =
+		StorageDoubleMap<_, Twox64Concat, MarketId, Twox64Concat, T::AccountId, Balance, ValueQuery>;
+
+	/// Average price the inventory of an account was filled at, weighted by quantity
+	#[pallet::storage]
+	#[pallet::getter(fn entry_price)]
+	pub(crate) type EntryPrice<T: Config> =
+		StorageDoubleMap<_, Twox64Concat, MarketId, Twox64Concat, T::AccountId, Price>;
+
+	/// What made the margin of an account
+	#[pallet::storage]
+	#[pallet::getter(fn account_stats)]
+	pub(crate) type Stats<T: Config> =
+		StorageDoubleMap<_, Twox64Concat, MarketId, Twox64Concat, T::AccountId, AccountStats, ValueQuery>;
 
+	/// Value of `CumulativeFunding` for the side of the inventory of the account when it was last settled
 	#[pallet::storage]
-	#[pallet::getter(fn inventory)]
-	pub(crate) type Inventory<T: Config> = StorageMap<_, Twox64Concat, T::AccountId, Amount, ValueQuery>;
+	pub(crate) type FundingIndex<T: Config> =
+		StorageDoubleMap<_, Twox64Concat, MarketId, Twox64Concat, T::AccountId, FixedI128, ValueQuery>;
 
+	/// PnL made so far per unit of long and of short `Inventory`, in native currency
 	#[pallet::storage]
-	#[pallet::getter(fn margin)]
-	pub(crate) type Margin<T: Config> = StorageMap<_, Twox64Concat, T::AccountId, Balance, ValueQuery>;
+	#[pallet::getter(fn cumulative_pnl)]
+	pub(crate) type CumulativePnl<T: Config> =
+		StorageMap<_, Twox64Concat, MarketId, (FixedI128, FixedI128), ValueQuery>;
 
+	/// Value of `CumulativePnl` for the side of the inventory of the account when it was last settled
 	#[pallet::storage]
-	pub(crate) type Price0<T: Config> = StorageValue<_, Price>;
+	pub(crate) type PnlIndex<T: Config> =
+		StorageDoubleMap<_, Twox64Concat, MarketId, Twox64Concat, T::AccountId, FixedI128, ValueQuery>;
+
+	/// Total long and short `Inventory` in a market
+	#[pallet::storage]
+	#[pallet::getter(fn total_inventory)]
+	pub(crate) type TotalInventory<T: Config> = StorageMap<_, Twox64Concat, MarketId, (Balance, Balance), ValueQuery>;
+
+	/// Resting orders, filled by `match_interest` once the price crosses their limit
+	#[pallet::storage]
+	#[pallet::getter(fn orders)]
+	pub(crate) type Orders<T: Config> = StorageDoubleMap<
+		_, Twox64Concat, MarketId, Twox64Concat, OrderId, Order<T::AccountId, T::BlockNumber>>;
+
+	/// Number of orders resting in a market per account
+	#[pallet::storage]
+	#[pallet::getter(fn open_orders)]
+	pub(crate) type OpenOrders<T: Config> =
+		StorageDoubleMap<_, Twox64Concat, MarketId, Twox64Concat, T::AccountId, u32, ValueQuery>;
+
+	/// Number of orders resting in a market
+	#[pallet::storage]
+	#[pallet::getter(fn total_orders)]
+	pub(crate) type TotalOrders<T: Config> = StorageMap<_, Twox64Concat, MarketId, u32, ValueQuery>;
+
+	#[pallet::storage]
+	#[pallet::getter(fn next_order_id)]
+	pub(crate) type NextOrderId<T: Config> = StorageValue<_, OrderId, ValueQuery>;
+
+	/// Next account of `InterestQueue` to be checked for liquidation
+	#[pallet::storage]
+	#[pallet::getter(fn liquidation_cursor)]
+	pub(crate) type LiquidationCursor<T: Config> = StorageMap<_, Twox64Concat, MarketId, T::AccountId>;
+
+	/// Accounts found close to liquidation, checked first at the next sweep, most at risk first
+	#[pallet::storage]
+	#[pallet::getter(fn liquidation_watchlist)]
+	pub(crate) type LiquidationWatchlist<T: Config> =
+		StorageMap<_, Twox64Concat, MarketId, Vec<T::AccountId>, ValueQuery>;
 
 	#[pallet::genesis_config]
-	pub struct GenesisConfig {}
+	pub struct GenesisConfig {
+		/// Markets listed at genesis, their ids are assigned in order
+		pub markets: Vec<MarketInfo>,
+	}
 
 	#[cfg(feature = "std")]
 	impl Default for GenesisConfig {
 		fn default() -> Self {
-			GenesisConfig {}
+			GenesisConfig {
+				markets: vec![],
+			}
 		}
 	}
 
 	#[pallet::genesis_build]
 	impl<T: Config> GenesisBuild<T> for GenesisConfig {
-		fn build(&self) {}
+		fn build(&self) {
+			for info in self.markets.iter() {
+				assert!(Pallet::<T>::ensure_valid_market(info).is_ok(), "Invalid market parameters");
+				Pallet::<T>::do_create_market(*info);
+			}
+		}
 	}
 
 	#[pallet::pallet]
@@ -155,29 +603,219 @@ pub mod module {
 
 	#[pallet::hooks]
 	impl<T: Config> Hooks<T::BlockNumber> for Pallet<T> {
-		fn on_initialize(_n: T::BlockNumber) -> Weight {
+		fn on_initialize(n: T::BlockNumber) -> Weight {
 			// TODO: this is called multiple times and not just at block start
-			Self::update_margin();
-			Self::liquidate();
-			Self::match_interest();
-			// TODO check this
-			10
+			let interval = T::FundingInterval::get();
+			let funding = !interval.is_zero() && (n % interval).is_zero();
+			let mut weight: Weight = 0;
+			for (market, _) in Markets::<T>::iter() {
+				weight = weight.saturating_add(T::DbWeight::get().reads(1))
+					.saturating_add(Self::update_margin(market));
+				if funding {
+					Self::accrue_funding(market);
+					weight = weight.saturating_add(T::WeightInfo::accrue_funding());
+				}
+				weight = weight.saturating_add(Self::liquidate_accounts(market))
+					.saturating_add(Self::match_interest(market));
+			}
+			weight
 		}
 
 		// TODO: this on seems to be called only once
 		fn on_finalize(_n: T::BlockNumber) {}
+
+		/// Scans the markets for accounts to liquidate, and submits an unsigned `liquidate_unsigned`
+		/// for each of them, most under-water first
+		fn offchain_worker(now: T::BlockNumber) {
+			let mut lock = StorageLock::<Time>::new(OFFCHAIN_LOCK);
+			if let Ok(_guard) = lock.try_lock() {
+				for (market, info) in Markets::<T>::iter() {
+					Self::submit_liquidations(now, market, &info);
+				}
+			}
+		}
+	}
+
+	#[pallet::validate_unsigned]
+	impl<T: Config> ValidateUnsigned for Pallet<T> {
+		type Call = Call<T>;
+
+		fn validate_unsigned(_source: TransactionSource, call: &Self::Call) -> TransactionValidity {
+			if let Call::liquidate_unsigned(market, who) = call {
+				let info = Self::markets(market).ok_or(InvalidTransaction::Call)?;
+				let price = Price0::<T>::get(market).ok_or(InvalidTransaction::Call)?;
+				let urgency = Self::liquidation_urgency(*market, &info, price, who)
+					.ok_or(InvalidTransaction::Stale)?;
+				ValidTransaction::with_tag_prefix("PerpetualAssetLiquidation")
+					.priority(T::UnsignedPriority::get().saturating_add(urgency.deconstruct().into()))
+					.and_provides((market, who))
+					.longevity(64)
+					.propagate(true)
+					.build()
+			} else {
+				InvalidTransaction::Call.into()
+			}
+		}
 	}
 	
 	#[pallet::call]
 	impl<T: Config> Pallet<T> {
-		#[pallet::weight(1000)]
+		#[pallet::weight(T::WeightInfo::create_market())]
+		/// Lists a new market
+		/// - `origin`: must be `UpdateOrigin`
+		/// - `currency_id`: the asset to be priced
+		/// - `initial_im_ratio`: the initial IM ratio
+		/// - `liquidation_ratio`: the liquidation ratio
+		/// - `transaction_fee`: the transaction fee
+		/// - `liquidation_penalty`: the liquidation penalty
+		/// - `liquidation_step`: the share of the inventory closed at each liquidation step
+		/// - `matching_policy`: how interest is matched
+		pub(super) fn create_market(
+			origin: OriginFor<T>,
+			currency_id: CurrencyId,
+			initial_im_ratio: Permill,
+			liquidation_ratio: Permill,
+			transaction_fee: Permill,
+			liquidation_penalty: Permill,
+			liquidation_step: Permill,
+			matching_policy: MatchingPolicy,
+		) -> DispatchResultWithPostInfo {
+			T::UpdateOrigin::ensure_origin(origin)?;
+			let info = MarketInfo {
+				currency_id,
+				initial_im_ratio,
+				liquidation_ratio,
+				transaction_fee,
+				liquidation_penalty,
+				liquidation_step,
+				matching_policy,
+			};
+			Self::ensure_valid_market(&info)?;
+			let market = Self::do_create_market(info);
+			Self::deposit_event(Event::MarketCreated(market, currency_id));
+			Ok(().into())
+		}
+
+		#[pallet::weight(T::WeightInfo::set_market_parameter())]
+		/// Sets the initial IM ratio of a market
+		/// - `origin`: must be `UpdateOrigin`
+		/// - `market`: the market to update
+		/// - `ratio`: the new initial IM ratio, must stay above the liquidation ratio
+		pub(super) fn set_initial_im_ratio(
+			origin: OriginFor<T>,
+			market: MarketId,
+			ratio: Permill,
+		) -> DispatchResultWithPostInfo {
+			T::UpdateOrigin::ensure_origin(origin)?;
+			Self::update_market(market, |info| info.initial_im_ratio = ratio)?;
+			Self::deposit_event(Event::InitialIMRatioUpdated(market, ratio));
+			Ok(().into())
+		}
+
+		#[pallet::weight(T::WeightInfo::set_market_parameter())]
+		/// Sets the liquidation ratio of a market
+		/// - `origin`: must be `UpdateOrigin`
+		/// - `market`: the market to update
+		/// - `ratio`: the new liquidation ratio, must stay below the initial IM ratio
+		pub(super) fn set_liquidation_ratio(
+			origin: OriginFor<T>,
+			market: MarketId,
+			ratio: Permill,
+		) -> DispatchResultWithPostInfo {
+			T::UpdateOrigin::ensure_origin(origin)?;
+			Self::update_market(market, |info| info.liquidation_ratio = ratio)?;
+			Self::deposit_event(Event::LiquidationRatioUpdated(market, ratio));
+			Ok(().into())
+		}
+
+		#[pallet::weight(T::WeightInfo::set_market_parameter())]
+		/// Sets the transaction fee of a market
+		/// - `origin`: must be `UpdateOrigin`
+		/// - `market`: the market to update
+		/// - `fee`: the new transaction fee
+		pub(super) fn set_transaction_fee(
+			origin: OriginFor<T>,
+			market: MarketId,
+			fee: Permill,
+		) -> DispatchResultWithPostInfo {
+			T::UpdateOrigin::ensure_origin(origin)?;
+			Self::update_market(market, |info| info.transaction_fee = fee)?;
+			Self::deposit_event(Event::TransactionFeeUpdated(market, fee));
+			Ok(().into())
+		}
+
+		#[pallet::weight(T::WeightInfo::set_market_parameter())]
+		/// Sets the liquidation penalty of a market
+		/// - `origin`: must be `UpdateOrigin`
+		/// - `market`: the market to update
+		/// - `penalty`: the new liquidation penalty
+		pub(super) fn set_liquidation_penalty(
+			origin: OriginFor<T>,
+			market: MarketId,
+			penalty: Permill,
+		) -> DispatchResultWithPostInfo {
+			T::UpdateOrigin::ensure_origin(origin)?;
+			Self::update_market(market, |info| info.liquidation_penalty = penalty)?;
+			Self::deposit_event(Event::LiquidationPenaltyUpdated(market, penalty));
+			Ok(().into())
+		}
+
+		#[pallet::weight(T::WeightInfo::set_market_parameter())]
+		/// Sets the liquidation step of a market
+		/// - `origin`: must be `UpdateOrigin`
+		/// - `market`: the market to update
+		/// - `step`: the share of the inventory closed at each liquidation step
+		pub(super) fn set_liquidation_step(
+			origin: OriginFor<T>,
+			market: MarketId,
+			step: Permill,
+		) -> DispatchResultWithPostInfo {
+			T::UpdateOrigin::ensure_origin(origin)?;
+			Self::update_market(market, |info| info.liquidation_step = step)?;
+			Self::deposit_event(Event::LiquidationStepUpdated(market, step));
+			Ok(().into())
+		}
+
+		#[pallet::weight(T::WeightInfo::set_market_parameter().saturating_add(
+			T::DbWeight::get().reads_writes(2, 2).saturating_mul(T::MaxPositions::get().into())))]
+		/// Sets the matching policy of a market. Going to `ProRata`, every account with interest
+		/// or inventory is matched again at the next block.
+		/// - `origin`: must be `UpdateOrigin`
+		/// - `market`: the market to update
+		/// - `policy`: how interest is matched from the next block
+		pub(super) fn set_matching_policy(
+			origin: OriginFor<T>,
+			market: MarketId,
+			policy: MatchingPolicy,
+		) -> DispatchResultWithPostInfo {
+			T::UpdateOrigin::ensure_origin(origin)?;
+			let previous = Self::markets(market).ok_or(Error::<T>::MarketNotFound)?.matching_policy;
+			Self::update_market(market, |info| info.matching_policy = policy)?;
+			if policy == MatchingPolicy::ProRata && previous != MatchingPolicy::ProRata {
+				for account in Self::interest_queue(market) {
+					ChangedInterest::<T>::insert(market, &account, true);
+				}
+				for (account, _) in Inventory::<T>::iter_prefix(market) {
+					ChangedInterest::<T>::insert(market, &account, true);
+				}
+			}
+			InterestChanged::<T>::insert(market, true);
+			Self::deposit_event(Event::MatchingPolicyUpdated(market, policy));
+			Ok(().into())
+		}
+
+		#[pallet::weight(T::WeightInfo::mint_or_burn(T::MaxPositions::get()))]
 		#[transactional]
 		/// Mints the payoff
 		/// - `origin`: the calling account
+		/// - `market`: the market to mint in
 		/// - `amount`: the amount of asset to be minted(can be positive or negative)
 		/// - `collateral`: the amount of collateral in native currency
+		///
+		/// Deprecated, use `trade` instead.
 		pub(super) fn mint_or_burn(
 			origin: OriginFor<T>,
+			market: MarketId,
 			#[pallet::compact] amount: Balance,
 			positive_amount: bool,
 			#[pallet::compact] collateral: Balance,
@@ -194,35 +832,274 @@ pub mod module {
 				col *= -1;
 			}
 
-			Self::mint(origin, amt, col)
+			Self::mint(origin, market, amt, col)
+		}
+
+		#[pallet::weight(T::WeightInfo::mint_or_burn(T::MaxPositions::get()))]
+		#[transactional]
+		/// Adds to the long or short interest of the caller, a long trade reducing a short interest
+		/// and the other way around
+		/// - `origin`: the calling account
+		/// - `market`: the market to trade in
+		/// - `side`: long or short
+		/// - `quantity`: the amount of asset
+		/// - `collateral`: the amount of collateral in native currency added to the margin,
+		/// or taken out of it if negative
+		///
+		/// `PositionDeposit` is reserved from the caller while it has interest in the market.
+		pub(super) fn trade(
+			origin: OriginFor<T>,
+			market: MarketId,
+			side: Side,
+			#[pallet::compact] quantity: Balance,
+			collateral: Amount,
+		) -> DispatchResultWithPostInfo {
+			let mut amount = Self::amount_try_from_balance(quantity)?;
+			if side == Side::Short {
+				amount *= -1;
+			}
+			Self::mint(origin, market, amount, collateral)
+		}
+
+		#[pallet::weight(T::WeightInfo::deposit_margin())]
+		#[transactional]
+		/// Adds collateral to the margin of the caller, without changing its position
+		/// - `origin`: the calling account
+		/// - `market`: the market of the margin
+		/// - `amount`: the amount of collateral in native currency
+		pub(super) fn deposit_margin(
+			origin: OriginFor<T>,
+			market: MarketId,
+			#[pallet::compact] amount: Balance,
+		) -> DispatchResultWithPostInfo {
+			let who = ensure_signed(origin)?;
+			Self::do_deposit_margin(who, market, amount)?;
+			Ok(().into())
+		}
+
+		#[pallet::weight(T::WeightInfo::withdraw_margin(T::MaxPositions::get()))]
+		#[transactional]
+		/// Takes collateral out of the margin of the caller, what is left must cover the IM
+		/// of its position at the last price
+		/// - `origin`: the calling account
+		/// - `market`: the market of the margin
+		/// - `amount`: the amount of collateral in native currency
+		pub(super) fn withdraw_margin(
+			origin: OriginFor<T>,
+			market: MarketId,
+			#[pallet::compact] amount: Balance,
+		) -> DispatchResultWithPostInfo {
+			let who = ensure_signed(origin)?;
+			Self::do_withdraw_margin(who, market, amount)?;
+			Ok(Some(T::WeightInfo::withdraw_margin(Self::positions(market))).into())
+		}
+
+		#[pallet::weight(T::WeightInfo::withdraw_margin(T::MaxPositions::get()))]
+		#[transactional]
+		/// Takes out all the margin of the caller above the IM of its position at the last price
+		/// - `origin`: the calling account
+		/// - `market`: the market of the margin
+		pub(super) fn withdraw_all(
+			origin: OriginFor<T>,
+			market: MarketId,
+		) -> DispatchResultWithPostInfo {
+			let who = ensure_signed(origin)?;
+			let margin = Self::settle_account(market, &who);
+			let free = Self::free_margin(market, &who, margin)?;
+			Self::do_withdraw_margin(who, market, free)?;
+			Ok(Some(T::WeightInfo::withdraw_margin(Self::positions(market))).into())
+		}
+
+		#[pallet::weight(T::WeightInfo::close_position(T::MaxPositions::get()))]
+		#[transactional]
+		/// Closes the whole position of the caller, paying the transaction fee on it,
+		/// and gives back all its margin
+		/// - `origin`: the calling account
+		/// - `market`: the market of the position
+		pub(super) fn close_position(
+			origin: OriginFor<T>,
+			market: MarketId,
+		) -> DispatchResultWithPostInfo {
+			let who = ensure_signed(origin)?;
+			let positions = Self::positions(market);
+			Self::do_close_position(who, market)?;
+			Ok(Some(T::WeightInfo::close_position(positions)).into())
+		}
+
+		#[pallet::weight(T::WeightInfo::place_order())]
+		/// Places a limit order, which adds to the interest of the caller in the first block
+		/// where the price is at or below the limit for a long, at or above it for a short.
+		/// IM is checked when the order is filled, and the order is dropped if there is not enough.
+		/// `OrderDeposit` is reserved from the caller until the order leaves the order book.
+		/// - `origin`: the calling account
+		/// - `market`: the market to trade in
+		/// - `side`: long or short
+		/// - `quantity`: the amount of asset
+		/// - `limit_price`: the worst price the order can be filled at
+		/// - `expiry`: the last block at which the order can be filled
+		pub(super) fn place_order(
+			origin: OriginFor<T>,
+			market: MarketId,
+			side: Side,
+			#[pallet::compact] quantity: Balance,
+			limit_price: Price,
+			expiry: T::BlockNumber,
+		) -> DispatchResultWithPostInfo {
+			let who = ensure_signed(origin)?;
+			ensure!(Markets::<T>::contains_key(market), Error::<T>::MarketNotFound);
+			ensure!(
+				!quantity.is_zero() && expiry >= frame_system::Pallet::<T>::block_number(),
+				Error::<T>::InvalidOrder
+			);
+			Self::amount_try_from_balance(quantity)?;
+			ensure!(
+				Self::open_orders(market, &who) < T::MaxOrdersPerAccount::get(),
+				Error::<T>::TooManyOrders
+			);
+			ensure!(Self::total_orders(market) < T::MaxOrders::get(), Error::<T>::OrderBookFull);
+			let deposit = T::OrderDeposit::get();
+			T::FeeCurrency::reserve(&who, deposit)?;
+
+			let id = NextOrderId::<T>::mutate(|id| {
+				let order = *id;
+				*id = id.saturating_add(1);
+				order
+			});
+			Orders::<T>::insert(market, id, Order {
+				owner: who.clone(),
+				side,
+				quantity,
+				limit_price,
+				expiry,
+				deposit,
+			});
+			OpenOrders::<T>::mutate(market, &who, |count| *count = count.saturating_add(1));
+			TotalOrders::<T>::mutate(market, |count| *count = count.saturating_add(1));
+			Self::deposit_event(Event::OrderPlaced(market, id, who, side, quantity, limit_price));
+			Ok(().into())
+		}
+
+		#[pallet::weight(T::WeightInfo::cancel_order())]
+		/// Cancels an order not filled yet, giving back its deposit
+		/// - `origin`: the account that placed the order
+		/// - `market`: the market of the order
+		/// - `id`: the order to cancel
+		pub(super) fn cancel_order(
+			origin: OriginFor<T>,
+			market: MarketId,
+			id: OrderId,
+		) -> DispatchResultWithPostInfo {
+			let who = ensure_signed(origin)?;
+			let order = Self::orders(market, id).ok_or(Error::<T>::OrderNotFound)?;
+			ensure!(order.owner == who, Error::<T>::NotOrderOwner);
+			Self::remove_order(market, id, &order);
+			Self::deposit_event(Event::OrderCancelled(market, id, who));
+			Ok(().into())
+		}
+
+		#[pallet::weight(T::WeightInfo::liquidate(T::MaxPositions::get()))]
+		#[transactional]
+		/// Liquidates an account without enough margin for its position, submitted by the offchain worker
+		/// - `origin`: none
+		/// - `market`: the market of the position
+		/// - `who`: the account to liquidate
+		pub(super) fn liquidate_unsigned(
+			origin: OriginFor<T>,
+			market: MarketId,
+			who: T::AccountId,
+		) -> DispatchResultWithPostInfo {
+			ensure_none(origin)?;
+			let info = Self::markets(market).ok_or(Error::<T>::MarketNotFound)?;
+			let price = Price0::<T>::get(market).ok_or(Error::<T>::PriceNotSet)?;
+			let positions = Self::positions(market);
+			ensure!(
+				Self::liquidate_account(market, &info, price, &who, None),
+				Error::<T>::NotLiquidatable
+			);
+			Ok(Some(T::WeightInfo::liquidate(positions)).into())
+		}
+
+		#[pallet::weight(T::WeightInfo::liquidate(T::MaxPositions::get()))]
+		#[transactional]
+		/// Liquidates an account without enough margin for its position,
+		/// the caller gets a share of the liquidation penalty
+		/// - `origin`: the calling account
+		/// - `market`: the market of the position
+		/// - `who`: the account to liquidate
+		pub(super) fn liquidate(
+			origin: OriginFor<T>,
+			market: MarketId,
+			who: T::AccountId,
+		) -> DispatchResultWithPostInfo {
+			let liquidator = ensure_signed(origin)?;
+			let info = Self::markets(market).ok_or(Error::<T>::MarketNotFound)?;
+			let price = Price0::<T>::get(market).ok_or(Error::<T>::PriceNotSet)?;
+			let positions = Self::positions(market);
+			ensure!(
+				Self::liquidate_account(market, &info, price, &who, Some(&liquidator)),
+				Error::<T>::NotLiquidatable
+			);
+			Ok(Some(T::WeightInfo::liquidate(positions)).into())
 		}
 	}
 }
 
 impl<T: Config> Pallet<T> {
+	fn do_create_market(info: MarketInfo) -> MarketId {
+		let market = NextMarketId::<T>::mutate(|id| {
+			let market = *id;
+			*id = id.saturating_add(1);
+			market
+		});
+		Markets::<T>::insert(market, info);
+		market
+	}
+
+	/// Applies `f` to the parameters of `market`, and stores them if they are still valid
+	fn update_market(market: MarketId, f: impl FnOnce(&mut MarketInfo)) -> DispatchResult {
+		Markets::<T>::try_mutate(market, |maybe_info| -> DispatchResult {
+			let info = maybe_info.as_mut().ok_or(Error::<T>::MarketNotFound)?;
+			f(info);
+			Self::ensure_valid_market(info)
+		})
+	}
+
+	/// Checks the invariants on the parameters of a market
+	fn ensure_valid_market(info: &MarketInfo) -> DispatchResult {
+		ensure!(info.liquidation_ratio < info.initial_im_ratio, Error::<T>::InvalidLiquidationRatio);
+		Ok(())
+	}
+
 	fn mint(
 		origin: OriginFor<T>,
+		market: MarketId,
 		amount: Amount,
 		collateral: Amount,
 	) -> DispatchResultWithPostInfo {
 		let who = ensure_signed(origin)?;
+		Self::do_mint(who, market, amount, collateral)?;
+		Ok(Some(T::WeightInfo::mint_or_burn(Self::positions(market))).into())
+	}
+
+	fn do_mint(
+		who: T::AccountId,
+		market: MarketId,
+		amount: Amount,
+		collateral: Amount,
+	) -> DispatchResultWithPostInfo {
+		let info = Self::markets(market).ok_or(Error::<T>::MarketNotFound)?;
+		Self::settle_account(market, &who);
 
-		let current_balance = Balances::<T>::try_get(who.clone()).unwrap_or(0.into());
+		let current_balance = Balances::<T>::try_get(market, who.clone()).unwrap_or(0.into());
 		let balance = current_balance.checked_add(amount).ok_or(Error::<T>::Overflow)?;
 
 		// Check if enough collateral
-		let current_margin = Self::amount_try_from_balance(Margin::<T>::try_get(who.clone()).unwrap_or(0u128.into()))?;
-		let price = Price0::<T>::get().ok_or(Error::<T>::PriceNotSet)?;
-		let positive_balance = Self::balance_try_from_amount_abs(balance)?;
-		let total_price = price.checked_mul_int(positive_balance).ok_or(Error::<T>::Overflow)?;
-		//TODO: very ugly
-		let pos_amount = Self::balance_try_from_amount_abs(amount)?;
-		let fee = price.checked_mul_int(pos_amount)
-				.and_then(|res| Some(T::TransactionFee::get().mul_ceil(res)))
-				.ok_or(Error::<T>::Overflow)?;
+		let current_margin = Self::amount_try_from_balance(
+			Margin::<T>::try_get(market, who.clone()).unwrap_or(0u128.into()))?;
+		let price = Price0::<T>::get(market).ok_or(Error::<T>::PriceNotSet)?;
+		let fee = Self::trading_fee(&info, price, amount)?;
 		let f = Self::amount_try_from_balance(fee)?;
-		let needed_im = Self::amount_try_from_balance(
-			T::InitialIMRatio::get().mul_ceil(total_price))?;
+		let needed_im = Self::amount_try_from_balance(Self::initial_margin(&info, price, balance)?)?;
 		let new_margin = current_margin.checked_add(collateral)
 				.and_then(|res| res.checked_sub(f))		
 				.ok_or(Error::<T>::Overflow)?;
@@ -230,40 +1107,348 @@ impl<T: Config> Pallet<T> {
 			return Err(Error::<T>::NotEnoughIM.into());
 		}
 
-		let module_account = Self::account_id();
 		let positive_margin = Self::balance_try_from_amount_abs(new_margin)?;
 		let positive_collateral = Self::balance_try_from_amount_abs(collateral)?;
+		ensure!(
+			current_balance != 0 || balance == 0 || Self::positions(market) < T::MaxPositions::get(),
+			Error::<T>::TooManyPositions
+		);
+		// Only withdrawals and closes may leave dust, which is then reaped
+		ensure!(
+			balance != 0 || current_balance != 0 || collateral < 0 || positive_margin >= T::MinimumMargin::get(),
+			Error::<T>::BelowMinimumMargin
+		);
 
 		if collateral > 0 {
-			// Transfer the collateral to the module's account
-			<T::Currency as MultiCurrency<T::AccountId>>::transfer(
-				T::NativeCurrencyId::get(),
-				&who,
-				&module_account,
-				positive_collateral)?;
+			Self::collect_collateral(&who, positive_collateral)?;
+			Stats::<T>::mutate(market, &who, |stats| stats.deposited = stats.deposited.saturating_add(positive_collateral));
 		}
 
 		if collateral < 0 {
-			// Transfer the collateral from the module's account
-			<T::Currency as MultiCurrency<T::AccountId>>::transfer(
-				T::NativeCurrencyId::get(),
-				&module_account,
-				&who,
-				positive_collateral)?;
+			Self::pay_collateral(market, &who, positive_collateral)?;
+			Stats::<T>::mutate(market, &who, |stats| stats.withdrawn = stats.withdrawn.saturating_add(positive_collateral));
+		}
+
+		if fee != 0 {
+			Self::charge_fee(market, &who, fee)?;
+		}
+
+		if collateral != 0 || fee != 0 {
+			Self::set_margin(market, &who, positive_margin);
 		}
 
 		if collateral != 0 {
-			Margin::<T>::insert(who.clone(), positive_margin);
-			Self::deposit_event(Event::CollateralUpdated(collateral));
+			Self::deposit_event(Event::CollateralUpdated(market, collateral));
+		}
+
+		if current_balance == 0 && balance != 0 {
+			let deposit = T::PositionDeposit::get();
+			T::FeeCurrency::reserve(&who, deposit)?;
+			PositionDeposits::<T>::insert(market, &who, deposit);
 		}
 
 		// Update the balances
-		Balances::<T>::insert(who.clone(), balance);
-		Self::deposit_event(Event::BalanceUpdated(who, balance));
+		Self::set_balance(market, &who, balance);
+		Self::deposit_event(Event::BalanceUpdated(market, who.clone(), balance));
+		Self::reap_if_dust(market, &who);
 
 		Ok(().into())
 	}
 
+	/// Adds `amount` to the margin of `who`
+	fn do_deposit_margin(who: T::AccountId, market: MarketId, amount: Balance) -> DispatchResult {
+		ensure!(Markets::<T>::contains_key(market), Error::<T>::MarketNotFound);
+		let margin = Self::settle_account(market, &who);
+		let new_margin = margin.checked_add(amount).ok_or(Error::<T>::Overflow)?;
+		ensure!(
+			Balances::<T>::contains_key(market, &who) || new_margin >= T::MinimumMargin::get(),
+			Error::<T>::BelowMinimumMargin
+		);
+		Self::collect_collateral(&who, amount)?;
+		Self::set_margin(market, &who, new_margin);
+		Stats::<T>::mutate(market, &who, |stats| stats.deposited = stats.deposited.saturating_add(amount));
+		Self::deposit_event(Event::MarginDeposited(market, who, amount));
+		Ok(())
+	}
+
+	/// Takes `amount` out of the margin of `who`, provided what is left covers the IM of its position
+	fn do_withdraw_margin(who: T::AccountId, market: MarketId, amount: Balance) -> DispatchResult {
+		let margin = Self::settle_account(market, &who);
+		ensure!(amount <= Self::free_margin(market, &who, margin)?, Error::<T>::NotEnoughIM);
+		Self::pay_collateral(market, &who, amount)?;
+		Self::set_margin(market, &who, margin - amount);
+		Stats::<T>::mutate(market, &who, |stats| stats.withdrawn = stats.withdrawn.saturating_add(amount));
+		Self::deposit_event(Event::MarginWithdrawn(market, who.clone(), amount));
+		Self::reap_if_dust(market, &who);
+		Ok(())
+	}
+
+	/// Clears the storage of `who` in `market` if it has no position and less than `MinimumMargin`,
+	/// what is left of its margin going to the treasury. Deposits cannot leave an account below
+	/// `MinimumMargin`, so this only reaps after a withdrawal, a close or a liquidation, or once
+	/// the inventory of a closed position is matched away.
+	fn reap_if_dust(market: MarketId, who: &T::AccountId) {
+		if Balances::<T>::contains_key(market, who) || Inventory::<T>::contains_key(market, who) ||
+			!(Margin::<T>::contains_key(market, who) || FundingIndex::<T>::contains_key(market, who) ||
+			PnlIndex::<T>::contains_key(market, who) || Stats::<T>::contains_key(market, who)) {
+			return;
+		}
+		let dust = Margin::<T>::get(market, who);
+		if dust >= T::MinimumMargin::get() {
+			return;
+		}
+		if !dust.is_zero() {
+			match T::FeeCurrency::withdraw(
+				&Self::account_id(),
+				dust,
+				WithdrawReasons::TRANSFER,
+				ExistenceRequirement::AllowDeath,
+			) {
+				Ok(imbalance) => T::Treasury::on_unbalanced(imbalance),
+				Err(_) => return, // The pool is short, keep the account for now
+			}
+		}
+		Self::remove_margin(market, who);
+		FundingIndex::<T>::remove(market, who);
+		PnlIndex::<T>::remove(market, who);
+		Stats::<T>::remove(market, who);
+		Self::deposit_event(Event::AccountReaped(market, who.clone(), dust));
+	}
+
+	/// Sets the balance of `who` to $0$, charging the transaction fee on it, pays back its margin
+	/// and clears its storage. Its inventory is rebalanced as if it was liquidated.
+	fn do_close_position(who: T::AccountId, market: MarketId) -> DispatchResult {
+		let info = Self::markets(market).ok_or(Error::<T>::MarketNotFound)?;
+		let margin = Self::settle_account(market, &who);
+		let balance = Balances::<T>::get(market, &who);
+		ensure!(balance != 0 || !margin.is_zero(), Error::<T>::NothingToClose);
+
+		let mut fee: Balance = 0;
+		if balance != 0 {
+			let price = Price0::<T>::get(market).ok_or(Error::<T>::PriceNotSet)?;
+			fee = Self::trading_fee(&info, price, balance)?.min(margin);
+		}
+		if fee != 0 {
+			Self::charge_fee(market, &who, fee)?;
+		}
+
+		Self::set_balance(market, &who, 0);
+		let inventory = Inventory::<T>::get(market, &who);
+		Self::write_inventory(market, &who, 0);
+		if inventory != 0 && info.matching_policy != MatchingPolicy::ProRata {
+			Self::rebalance_inventory(market, &who, Self::balance_try_from_amount_abs(inventory)?, inventory > 0);
+		}
+		let released = margin - fee;
+		Self::pay_collateral(market, &who, released)?;
+
+		Balances::<T>::remove(market, &who);
+		Self::remove_margin(market, &who);
+		FundingIndex::<T>::remove(market, &who);
+		PnlIndex::<T>::remove(market, &who);
+		Stats::<T>::remove(market, &who);
+		Self::deposit_event(Event::PositionClosed(market, who, balance, released));
+		Ok(())
+	}
+
+	/// Share of `margin` above the IM of the position of `who` at $P_0$
+	fn free_margin(market: MarketId, who: &T::AccountId, margin: Balance) -> result::Result<Balance, Error<T>> {
+		let info = Self::markets(market).ok_or(Error::<T>::MarketNotFound)?;
+		let balance = Balances::<T>::get(market, who);
+		if balance == 0 {
+			return Ok(margin);
+		}
+		let price = Price0::<T>::get(market).ok_or(Error::<T>::PriceNotSet)?;
+		Ok(margin.saturating_sub(Self::initial_margin(&info, price, balance)?))
+	}
+
+	/// IM needed in `info` for a position of `balance` at `price`
+	fn initial_margin(info: &MarketInfo, price: Price, balance: Amount) -> result::Result<Balance, Error<T>> {
+		let total_price = price.checked_mul_int(Self::balance_try_from_amount_abs(balance)?)
+			.ok_or(Error::<T>::Overflow)?;
+		Ok(info.initial_im_ratio.mul_ceil(total_price))
+	}
+
+	/// Transaction fee of `info` on trading `amount` at `price`
+	fn trading_fee(info: &MarketInfo, price: Price, amount: Amount) -> result::Result<Balance, Error<T>> {
+		let total_price = price.checked_mul_int(Self::balance_try_from_amount_abs(amount)?)
+			.ok_or(Error::<T>::Overflow)?;
+		Ok(info.transaction_fee.mul_ceil(total_price))
+	}
+
+	/// Moves `amount` of collateral from `who` to the module's account
+	fn collect_collateral(who: &T::AccountId, amount: Balance) -> DispatchResult {
+		<T::Currency as MultiCurrency<T::AccountId>>::transfer(
+			T::NativeCurrencyId::get(),
+			who,
+			&Self::account_id(),
+			amount)
+	}
+
+	/// Pays `amount` of collateral back to `who` from the module's account. If the pool is under-water,
+	/// everyone only gets back their share of it, and the rest is recorded as a socialized loss.
+	fn pay_collateral(market: MarketId, who: &T::AccountId, amount: Balance) -> DispatchResult {
+		let payout = Self::redeemable(amount);
+		let loss = amount - payout;
+		if loss != 0 {
+			SocializedLosses::<T>::mutate(market, who.clone(), |total| *total = total.saturating_add(loss));
+			Self::deposit_event(Event::LossSocialized(market, who.clone(), loss));
+		}
+		<T::Currency as MultiCurrency<T::AccountId>>::transfer(
+			T::NativeCurrencyId::get(),
+			&Self::account_id(),
+			who,
+			payout)
+	}
+
+	/// Moves `fee`, already taken from the margin of `who`, out of the module's account
+	/// and into the insurance fund and the treasury
+	fn charge_fee(market: MarketId, who: &T::AccountId, fee: Balance) -> DispatchResult {
+		let insurance = T::InsuranceFundShare::get().mul_floor(fee);
+		if !insurance.is_zero() {
+			<T::Currency as MultiCurrency<T::AccountId>>::transfer(
+				T::NativeCurrencyId::get(),
+				&Self::account_id(),
+				&Self::insurance_fund_account(),
+				insurance)?;
+			Self::deposit_event(Event::InsuranceFundDeposited(market, insurance));
+		}
+		let imbalance = T::FeeCurrency::withdraw(
+			&Self::account_id(),
+			fee - insurance,
+			WithdrawReasons::FEE,
+			ExistenceRequirement::AllowDeath)?;
+		T::Treasury::on_unbalanced(imbalance);
+		TotalFeesCollected::<T>::mutate(|total| *total = total.saturating_add(fee));
+		Stats::<T>::mutate(market, who, |stats| stats.fees_paid = stats.fees_paid.saturating_add(fee));
+		Self::deposit_event(Event::FeeCharged(market, who.clone(), fee));
+		Ok(())
+	}
+
+	/// Liquidates the accounts of `market` as per `liquidate_account`, checking at most
+	/// `MaxLiquidationsPerBlock` of them. The accounts of the watchlist are checked first, then
+	/// the sweep carries on through `InterestQueue` from the cursor, starting over once at the end.
+	/// Only the accounts checked are read from the queue.
+	/// Accounts left with less than twice the margin needed not to be liquidated make up the
+	/// watchlist of the next sweep, capped to half the accounts checked per block.
+	/// Returns the weight consumed.
+	fn liquidate_accounts(market: MarketId) -> Weight {
+		let db = T::DbWeight::get();
+		let price = Price0::<T>::get(market);
+		let info = Self::markets(market);
+		let (price, info) = match (price, info) {
+			(Some(price), Some(info)) => (price, info),
+			_ => return db.reads(2), // Price or market not set, do nothing
+		};
+
+		let max = T::MaxLiquidationsPerBlock::get() as usize;
+		if max == 0 {
+			return db.reads(2);
+		}
+		let mut checked: Vec<T::AccountId> = Vec::new();
+		let mut watch: Vec<(Price, T::AccountId)> = Vec::new();
+
+		let mut liquidated: u32 = 0;
+		let mut check = |account: &T::AccountId, checked: &mut Vec<T::AccountId>| {
+			if !checked.contains(account) {
+				checked.push(account.clone());
+				if Self::liquidate_account(market, &info, price, account, None) {
+					liquidated += 1;
+				}
+				if let Some(health) = Self::health(market, &info, price, account) {
+					watch.push((health, account.clone()));
+				}
+			}
+		};
+
+		for account in Self::liquidation_watchlist(market) {
+			check(&account, &mut checked);
+		}
+		let mut next = Self::liquidation_cursor(market).or_else(|| InterestQueueEnds::<T>::get(market).head);
+		while let Some(account) = next {
+			if checked.len() >= max {
+				break;
+			}
+			// Moved on before checking, so that the cursor follows the queue if the account leaves it
+			let following = InterestQueue::<T>::get(market, &account).and_then(|links| links.next);
+			Self::set_liquidation_cursor(market, following);
+			check(&account, &mut checked);
+			next = Self::liquidation_cursor(market);
+		}
+
+		watch.sort_by(|a, b| a.0.cmp(&b.0));
+		LiquidationWatchlist::<T>::insert(market,
+			watch.into_iter().take(max / 2).map(|(_, account)| account).collect::<Vec<_>>());
+
+		let rebalance = T::WeightInfo::rebalance_inventory(Self::positions(market));
+		T::WeightInfo::liquidate_accounts(checked.len() as u32)
+			.saturating_add(rebalance.saturating_mul(liquidated as Weight))
+	}
+
+	/// Sets the next account of `market` to be checked for liquidation, `None` to start over
+	fn set_liquidation_cursor(market: MarketId, account: Option<T::AccountId>) {
+		match account {
+			Some(account) => LiquidationCursor::<T>::insert(market, account),
+			None => LiquidationCursor::<T>::remove(market),
+		}
+	}
+
+	/// Submits the liquidation of the accounts of `market` found under-water at the last price,
+	/// most under-water first. An account is submitted again only after `UnsignedInterval` blocks.
+	fn submit_liquidations(now: T::BlockNumber, market: MarketId, info: &MarketInfo) {
+		let price = match Price0::<T>::get(market) {
+			Some(price) => price,
+			None => return,
+		};
+		let mut accounts: Vec<(Permill, T::AccountId)> = Margin::<T>::iter_prefix(market)
+			.filter_map(|(account, _)| Self::liquidation_urgency(market, info, price, &account)
+				.map(|urgency| (urgency, account)))
+			.collect();
+		accounts.sort_by(|a, b| b.0.cmp(&a.0));
+
+		for (_, account) in accounts {
+			let key = (OFFCHAIN_SUBMITTED, market, &account).encode();
+			let last = sp_io::offchain::local_storage_get(StorageKind::PERSISTENT, &key)
+				.and_then(|raw| T::BlockNumber::decode(&mut &raw[..]).ok());
+			if let Some(last) = last {
+				if now < last.saturating_add(T::UnsignedInterval::get()) {
+					continue;
+				}
+			}
+			let call = Call::liquidate_unsigned(market, account);
+			if SubmitTransaction::<T, Call<T>>::submit_unsigned_transaction(call.into()).is_ok() {
+				sp_io::offchain::local_storage_set(StorageKind::PERSISTENT, &key, &now.encode());
+			}
+		}
+	}
+
+	/// If `account` can be liquidated, returns how far under-water it is: the share of the
+	/// margin it needs that is missing, its PnL and funding being settled
+	fn liquidation_urgency(market: MarketId, info: &MarketInfo, price: Price, account: &T::AccountId) -> Option<Permill> {
+		let inventory = Self::balance_try_from_amount_abs(Self::inventory(market, account)).ok()?;
+		let balance = Self::balance_try_from_amount_abs(Balances::<T>::get(market, account)).ok()?;
+		let margin = Self::margin(market, account);
+
+		let for_inventory = info.liquidation_ratio.mul_ceil(price.saturating_mul_int(inventory));
+		let for_balance = info.liquidation_ratio.mul_ceil(price.saturating_mul_int(balance));
+		if (inventory.is_zero() || for_inventory < margin) && for_balance <= margin {
+			return None;
+		}
+		let needed = for_inventory.max(for_balance);
+		Some(Permill::from_rational(needed.saturating_sub(margin), needed))
+	}
+
+	/// Ratio of the margin of `account` to the margin it needs not to be liquidated,
+	/// if it is below $2$ so the account should be watched
+	fn health(market: MarketId, info: &MarketInfo, price: Price, account: &T::AccountId) -> Option<Price> {
+		let balance = Self::balance_try_from_amount_abs(Balances::<T>::get(market, account)).ok()?;
+		let needed = info.liquidation_ratio.mul_ceil(price.saturating_mul_int(balance));
+		let margin = Self::margin(market, account);
+		if needed.is_zero() || margin >= needed.saturating_mul(2) {
+			return None;
+		}
+		Price::checked_from_rational(margin, needed)
+	}
+
 	/// Call *M* the total margin for a participant *A*,
 	/// Call *T* the total interest, and *B* the inventory (open interest is $T - B$)
 	/// The needed collateral for maintaining the inventory is $B * P_0 * L$
@@ -282,128 +1467,999 @@ impl<T: Config> Pallet<T> {
 	/// it does not suffer from immediate liquidation.
 	/// 
 	/// ### Liquidation of inventory
-	/// If $B * P_0 * L >= M$, liquidate the full position
-	/// so total position and inventory goes to $0$
-	fn liquidate() {
-		let price = Price0::<T>::get();
-		if price.is_some() {
-			let price = price.unwrap();
-			let liq_div = T::LiquidationRatio::get();
-			let im_div = T::InitialIMRatio::get();
-
-			for (account, margin) in Margin::<T>::iter() {
-				let inventory_signed = Self::inventory(account.clone());
-				let inventory = Self::balance_try_from_amount_abs(inventory_signed).unwrap(); // TODO handle overflow better
-				let balance = Self::balance_try_from_amount_abs(
-					Balances::<T>::get(account.clone())).unwrap(); // TODO handle overflow better
-
-				// am I in liquidation? TODO check those saturating multiplications
-				if liq_div.mul_ceil(price.saturating_mul_int(inventory)) >= margin { // Yes I am
-					Balances::<T>::insert(account.clone(), 0);
-					Inventory::<T>::insert(account, 0);
-				} else if liq_div.mul_ceil(price.saturating_mul_int(balance)) > margin {
-					if price.is_zero() || im_div.mul_ceil(price.saturating_mul_int(inventory)) > margin {
-						Balances::<T>::insert(account, inventory_signed);
-					} else {
-						// TODO is this safe?
-						let new_balance = price.reciprocal().unwrap().saturating_mul_int(
-							im_div.saturating_reciprocal_mul_floor(margin)
-						);
-						// TODO: handle overflow better
-						let mut n = Self::amount_try_from_balance(new_balance).unwrap();
-						if inventory_signed < 0 {
-							n *= -1;
-						}
-						Balances::<T>::insert(account, n);
-					}
-				} // Nothing to do in this case	
+	/// If $B * P_0 * L >= M$, close the inventory by steps of `liquidation_step` of *B*
+	/// until what is left of it, *B'*, verifies $B' * P_0 * L < M$, as per `liquidation_remainder`.
+	/// Total position and inventory both go to *B'*, which is $0$ if `liquidation_step` is $0$ or $100\%$.
+	/// The liquidation penalty is then taken out of the share of *M* backing the closed inventory.
+	/// The `liquidator`, if any, gets `LiquidatorShare` of it and the rest goes to the insurance fund.
+	///
+	/// Returns `false` if there was nothing to liquidate.
+	fn liquidate_account(
+		market: MarketId,
+		info: &MarketInfo,
+		price: Price,
+		account: &T::AccountId,
+		liquidator: Option<&T::AccountId>,
+	) -> bool {
+		let liq_div = info.liquidation_ratio;
+		let im_div = info.initial_im_ratio;
+
+		let inventory_signed = Self::inventory(market, account);
+		let inventory = Self::balance_try_from_amount_abs(inventory_signed).unwrap(); // TODO handle overflow better
+		let balance = Self::balance_try_from_amount_abs(
+			Balances::<T>::get(market, account.clone())).unwrap(); // TODO handle overflow better
+		if inventory.is_zero() && balance.is_zero() {
+			return false; // No position, nothing to liquidate whatever the margin
+		}
+		let margin = Self::settle_account(market, account);
+
+		// am I in liquidation? TODO check those saturating multiplications
+		// Only open interest is left without inventory, the close-out below takes care of it
+		if !inventory.is_zero() && liq_div.mul_ceil(price.saturating_mul_int(inventory)) >= margin { // Yes I am
+			let step = if info.liquidation_step.is_zero() {
+				inventory
+			} else {
+				info.liquidation_step.mul_ceil(inventory).max(1)
+			};
+			let remaining = Self::liquidation_remainder(liq_div, price, inventory, step, margin);
+			let closed = inventory - remaining;
+
+			let mut remaining_signed = Self::amount_try_from_balance(remaining).unwrap(); // Smaller than inventory
+			let mut closed_signed = Self::amount_try_from_balance(closed).unwrap(); // Smaller than inventory
+			if inventory_signed < 0 {
+				remaining_signed *= -1;
+				closed_signed *= -1;
+			}
+			Self::set_balance(market, account, remaining_signed);
+			Self::update_inventory(market, account, remaining_signed);
+			Self::deposit_event(Event::PositionLiquidated(market, account.clone(), closed_signed, remaining_signed));
+			// Pro-rata, the other accounts follow the totals of `Inventory`
+			if !closed.is_zero() && info.matching_policy != MatchingPolicy::ProRata {
+				Self::rebalance_inventory(market, account, closed, inventory_signed > 0);
+			}
+
+			let released = multiply_by_rational(margin, closed, inventory).unwrap_or(margin);
+			let penalty = Self::charge_liquidation_penalty(market, info, account, margin, released, liquidator);
+			Self::deposit_event(Event::Liquidated(market, account.clone(), penalty));
+			Self::reap_if_dust(market, account);
+			true
+		} else if liq_div.mul_ceil(price.saturating_mul_int(balance)) > margin {
+			if price.is_zero() || im_div.mul_ceil(price.saturating_mul_int(inventory)) > margin {
+				Self::set_balance(market, account, inventory_signed);
+			} else {
+				// TODO is this safe?
+				let new_balance = price.reciprocal().unwrap().saturating_mul_int(
+					im_div.saturating_reciprocal_mul_floor(margin)
+				);
+				// TODO: handle overflow better
+				let mut n = Self::amount_try_from_balance(new_balance).unwrap();
+				if inventory_signed < 0 {
+					n *= -1;
+				}
+				Self::set_balance(market, account, n);
+			}
+			true
+		} else {
+			false // Nothing to do in this case
+		}
+	}
+
+	/// What is left of `inventory` once closed by the least number of `step` for it to verify
+	/// $B' * P_0 * L < M$, or $0$. The number of steps is first taken as $(B - M / (L * P_0)) / step$,
+	/// then made exact with a binary search around it, so this does not depend on the size of `step`.
+	/// A zero `step` closes the whole inventory at once.
+	fn liquidation_remainder(liq_div: Permill, price: Price, inventory: Balance, step: Balance, margin: Balance) -> Balance {
+		if step.is_zero() {
+			return 0;
+		}
+		let left_after = |steps: Balance| inventory.saturating_sub(steps.saturating_mul(step));
+		let done = |steps: Balance| {
+			let left = left_after(steps);
+			left.is_zero() || liq_div.mul_ceil(price.saturating_mul_int(left)) < margin
+		};
+		let max_steps = inventory / step + if (inventory % step).is_zero() { 0 } else { 1 };
+		// Largest inventory the margin keeps out of liquidation, with no margin there is none
+		let kept = if margin.is_zero() {
+			0
+		} else {
+			Price::from(liq_div).saturating_mul(price).reciprocal().map_or(inventory, |r| r.saturating_mul_int(margin))
+		};
+		let to_close = inventory.saturating_sub(kept);
+		let guess = (to_close / step + if (to_close % step).is_zero() { 0 } else { 1 }).min(max_steps);
+		let (mut low, mut high) = if done(guess) { (0, guess) } else { (guess + 1, max_steps) };
+		while low < high {
+			let middle = low + (high - low) / 2;
+			if done(middle) {
+				high = middle;
+			} else {
+				low = middle + 1;
 			}
-		} // Price not set, do nothing
+		}
+		left_after(low)
+	}
+
+	/// Keeps $\sum BI_i = \sum SI_i$ in a market matched by priority once `closed` of the inventory
+	/// of `liquidated` is gone.
+	/// Open interest on the same side, `long` or short, takes it over first, pro-rata to how much
+	/// of it is unmatched. What is left is removed pro-rata from the inventory of the opposite side,
+	/// which goes back to being open interest.
+	///
+	/// Open interest is only looked for in `InterestQueue`, and inventory is only held by the accounts
+	/// of the queue and those which left it since the last match, so both scans are bounded by about
+	/// `MaxPositions` accounts, as per `WeightInfo::rebalance_inventory`.
+	fn rebalance_inventory(market: MarketId, liquidated: &T::AccountId, closed: Balance, long: bool) {
+		let mut open: Vec<(T::AccountId, Balance)> = Vec::new();
+		for account in Self::interest_queue(market) {
+			let balance = Balances::<T>::get(market, &account);
+			let inventory = Inventory::<T>::get(market, &account);
+			if account == *liquidated || balance == 0 || (balance > 0) != long ||
+				(inventory != 0 && (inventory > 0) != long) {
+				continue;
+			}
+			let unmatched = Self::balance_try_from_amount_abs(balance).unwrap_or(0)
+				.saturating_sub(Self::balance_try_from_amount_abs(inventory).unwrap_or(0));
+			if !unmatched.is_zero() {
+				open.push((account, unmatched));
+			}
+		}
+		let mut opposite: Vec<(T::AccountId, Balance)> = Vec::new();
+		for (account, inventory) in Inventory::<T>::iter_prefix(market) {
+			if inventory != 0 && (inventory > 0) != long {
+				opposite.push((account, Self::balance_try_from_amount_abs(inventory).unwrap_or(0)));
+			}
+		}
+
+		let total_open = open.iter().fold(0u128, |total, (_, b)| total.saturating_add(*b));
+		let taken_over = closed.min(total_open);
+		let total_opposite = opposite.iter().fold(0u128, |total, (_, b)| total.saturating_add(*b));
+		let removed = (closed - taken_over).min(total_opposite);
+
+		let sign: Amount = if long { 1 } else { -1 };
+		for ((account, _), amount) in open.iter().zip(Self::pro_rata(taken_over, &open)) {
+			Self::shift_inventory(market, account, sign.saturating_mul(Self::amount_try_from_balance(amount).unwrap_or(0)));
+		}
+		for ((account, _), amount) in opposite.iter().zip(Self::pro_rata(removed, &opposite)) {
+			Self::shift_inventory(market, account, sign.saturating_mul(Self::amount_try_from_balance(amount).unwrap_or(0)));
+		}
+
+		Self::deposit_event(Event::InventoryRebalanced(market, taken_over, removed));
+	}
+
+	/// Adds `amount` to the inventory of `account`, settling the PnL and funding accrued on the old one first.
+	/// An account left with no position is reaped if it is dust.
+	fn shift_inventory(market: MarketId, account: &T::AccountId, amount: Amount) {
+		if amount == 0 {
+			return;
+		}
+		Self::settle_account(market, account);
+		Self::write_inventory(market, account, Inventory::<T>::get(market, account).saturating_add(amount));
+		Self::reap_if_dust(market, account);
+	}
+
+	/// Splits `total` pro-rata to the weights of `weights`, the units lost rounding down going to the
+	/// largest remainders, and to the smallest key for equal remainders. The shares always add up to `total`.
+	/// `total` must not be more than the sum of the weights, so that no share exceeds its weight.
+	pub(crate) fn pro_rata<K: Ord>(total: Balance, weights: &[(K, Balance)]) -> Vec<Balance> {
+		let sum = weights.iter().fold(0u128, |sum, (_, w)| sum.saturating_add(*w));
+		if sum.is_zero() {
+			return weights.iter().map(|_| 0).collect();
+		}
+		let mut shares: Vec<Balance> = weights.iter()
+			.map(|(_, w)| multiply_by_rational(*w, total, sum).unwrap_or(0))
+			.collect();
+		// The remainder is below `sum` so wrapping arithmetic gets it exactly
+		let mut remainders: Vec<(usize, Balance)> = weights.iter().zip(shares.iter()).enumerate()
+			.map(|(i, ((_, w), share))| (i, w.wrapping_mul(total).wrapping_sub(share.wrapping_mul(sum))))
+			.collect();
+		remainders.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| weights[a.0].0.cmp(&weights[b.0].0)));
+		let left = total.saturating_sub(shares.iter().fold(0u128, |total, s| total.saturating_add(*s)));
+		for (i, _) in remainders.into_iter().take(left.saturated_into::<usize>()) {
+			shares[i] += 1;
+		}
+		shares
+	}
+
+	/// Takes the liquidation penalty on `released`, the part of `margin` backing
+	/// the liquidated inventory of `account`, and returns what was charged. Only the shares
+	/// actually transferred to the liquidator and to the insurance fund are taken from the margin.
+	fn charge_liquidation_penalty(
+		market: MarketId,
+		info: &MarketInfo,
+		account: &T::AccountId,
+		margin: Balance,
+		released: Balance,
+		liquidator: Option<&T::AccountId>,
+	) -> Balance {
+		let penalty = info.liquidation_penalty.mul_floor(released);
+		let module_account = Self::account_id();
+		let native = T::NativeCurrencyId::get();
+		if penalty.is_zero() ||
+			<T::Currency as MultiCurrency<T::AccountId>>::free_balance(native, &module_account) < penalty {
+			return 0;
+		}
+
+		let mut charged: Balance = 0;
+		let reward = liquidator.map_or(0, |_| T::LiquidatorShare::get().mul_floor(penalty));
+		if let Some(liquidator) = liquidator {
+			if !reward.is_zero() &&
+				<T::Currency as MultiCurrency<T::AccountId>>::transfer(native, &module_account, liquidator, reward).is_ok() {
+				charged = charged.saturating_add(reward);
+				Self::deposit_event(Event::LiquidatorRewarded(market, liquidator.clone(), reward));
+			}
+		}
+
+		let insurance = penalty - reward;
+		if !insurance.is_zero() &&
+			<T::Currency as MultiCurrency<T::AccountId>>::transfer(
+				native, &module_account, &Self::insurance_fund_account(), insurance).is_ok() {
+			charged = charged.saturating_add(insurance);
+			Self::deposit_event(Event::InsuranceFundDeposited(market, insurance));
+		}
+
+		Self::set_margin(market, account, margin - charged);
+		Stats::<T>::mutate(market, account, |stats| stats.fees_paid = stats.fees_paid.saturating_add(charged));
+		charged
+	}
+
+	/// Fills the orders of `market` whose limit is compatible with $P_0$, oldest first and at most
+	/// `MaxOrderFillsPerBlock` of them, and drops the expired ones as well as those without enough
+	/// margin to be filled. Returns the weight consumed.
+	fn fill_orders(market: MarketId) -> Weight {
+		let db = T::DbWeight::get();
+		let price = match Price0::<T>::get(market) {
+			Some(price) => price,
+			None => return db.reads(1),
+		};
+		let now = frame_system::Pallet::<T>::block_number();
+		let mut orders: Vec<(OrderId, Order<T::AccountId, T::BlockNumber>)> = Orders::<T>::iter_prefix(market).collect();
+		orders.sort_by_key(|(id, _)| *id);
+		let max_fills = T::MaxOrderFillsPerBlock::get();
+		// The orders not filled are at most read, and dropped when expired
+		let weight = T::WeightInfo::fill_orders(max_fills.min(orders.len() as u32))
+			.saturating_add(db.reads_writes(2, 4).saturating_mul(orders.len() as Weight));
+		let mut fills: u32 = 0;
+		for (id, order) in orders {
+			if order.expiry < now {
+				Self::remove_order(market, id, &order);
+				Self::deposit_event(Event::OrderCancelled(market, id, order.owner));
+				continue;
+			}
+			let mut amount = match Self::amount_try_from_balance(order.quantity) {
+				Ok(amount) => amount,
+				Err(_) => continue,
+			};
+			match order.side {
+				Side::Long if price > order.limit_price => continue,
+				Side::Short if price < order.limit_price => continue,
+				Side::Short => amount *= -1,
+				Side::Long => {},
+			}
+			if fills >= max_fills {
+				continue;
+			}
+			fills += 1;
+			Self::remove_order(market, id, &order);
+			let filled = with_transaction(|| match Self::do_mint(order.owner.clone(), market, amount, 0) {
+				Ok(_) => TransactionOutcome::Commit(true),
+				Err(_) => TransactionOutcome::Rollback(false),
+			});
+			if filled {
+				Self::deposit_event(Event::OrderFilled(market, id, order.owner));
+			} else {
+				Self::deposit_event(Event::OrderCancelled(market, id, order.owner));
+			}
+		}
+		weight
+	}
+
+	/// Takes order `id` of `market` out of the order book, giving back its deposit
+	fn remove_order(market: MarketId, id: OrderId, order: &Order<T::AccountId, T::BlockNumber>) {
+		Orders::<T>::remove(market, id);
+		T::FeeCurrency::unreserve(&order.owner, order.deposit);
+		let open = Self::open_orders(market, &order.owner).saturating_sub(1);
+		if open == 0 {
+			OpenOrders::<T>::remove(market, &order.owner);
+		} else {
+			OpenOrders::<T>::insert(market, &order.owner, open);
+		}
+		TotalOrders::<T>::mutate(market, |count| *count = count.saturating_sub(1));
 	}
 
 	/// If $\forall i, X_i = 0$ then no interest to match. Otherwise, call $R = \frac{\sum_i Y_i}{\sum_i X_i}$
 	/// $B_i$ has bought $min(X_i, X_i * R)$
 	/// $S_i$ has sold $min(Y_i, Y_i / R)$
-	fn match_interest() {
-		// TODO: only run if needed
-		// Reset inventory
-		Inventory::<T>::remove_all();
-		let mut shorts: Balance = 0u128;
-		let mut longs: Balance = 0u128;
-		for balance in Balances::<T>::iter_values() {
-			let b = Self::balance_try_from_amount_abs(balance).unwrap(); // TODO Panics if error
-			if balance < 0 {
-				shorts += b;
-			} else {
-				longs += b;
+	///
+	/// This only runs when some interest changed since the last match. Pro-rata, only the accounts
+	/// whose interest changed are matched: their interest goes into `Inventory`, from which every
+	/// inventory is derived with the totals, see `inventory`. So the cost is proportional to them.
+	/// By priority, every account with interest or inventory in `market` is read instead, at most
+	/// about `MaxPositions`, and only the inventories that change are written.
+	/// Returns the weight consumed.
+	fn match_interest(market: MarketId) -> Weight {
+		let db = T::DbWeight::get();
+		let mut weight = Self::fill_orders(market).saturating_add(db.reads_writes(1, 1));
+		if !InterestChanged::<T>::take(market) {
+			return weight;
+		}
+		let changed = ChangedInterest::<T>::drain_prefix(market).map(|(account, _)| account).collect::<Vec<_>>();
+		let policy = Self::markets(market).map_or(MatchingPolicy::ProRata, |info| info.matching_policy);
+		if policy == MatchingPolicy::ProRata {
+			weight = weight.saturating_add(T::WeightInfo::match_interest(changed.len() as u32));
+			for account in changed {
+				let balance = Balances::<T>::get(market, &account);
+				if balance != Inventory::<T>::get(market, &account) {
+					Self::update_inventory(market, &account, balance);
+					Self::reap_if_dust(market, &account);
+				}
 			}
+			return weight;
 		}
 
+		let (longs, shorts) = Self::total_interest(market);
 		// If one of them is 0, nothing to match
-		if shorts != 0 && longs != 0 {
-			let ratio;
-			let shorts_filled;
-			if shorts < longs {
-				ratio = Perquintill::from_rational(shorts, longs);
-				shorts_filled = true;
+		let matched = if shorts == 0 || longs == 0 {
+			Vec::new()
+		} else {
+			Self::match_by_priority(market, policy, longs, shorts)
+		};
+
+		let mut target: BTreeMap<T::AccountId, Amount> = matched.into_iter().collect();
+		let held = Inventory::<T>::iter_prefix(market).collect::<Vec<_>>();
+		// Accounts left with inventory but no interest are read as well as those with interest
+		let accounts = target.len().max(held.len()).max(changed.len()) as u32;
+		weight = weight.saturating_add(T::WeightInfo::match_interest(accounts));
+		for (account, inventory) in held {
+			let new_inventory = target.remove(&account).unwrap_or(0);
+			if new_inventory != inventory {
+				Self::update_inventory(market, &account, new_inventory);
+				Self::reap_if_dust(market, &account);
+			}
+		}
+		for (account, new_inventory) in target {
+			if new_inventory != 0 {
+				Self::update_inventory(market, &account, new_inventory);
+			}
+		}
+		weight
+	}
+
+	/// Sets the inventory of `account`, settling the PnL and funding accrued on the old one first
+	fn update_inventory(market: MarketId, account: &T::AccountId, inventory: Amount) {
+		Self::settle_account(market, account);
+		Self::write_inventory(market, account, inventory);
+	}
+
+	/// Stores the inventory of `account`, which must be settled, keeping `TotalInventory` up to date.
+	/// `PnlIndex` and `FundingIndex` follow the side of the inventory when it switches. The change is filled at $P_0$,
+	/// and the entry price of the inventory updated as per `entry_price_after`.
+	fn write_inventory(market: MarketId, account: &T::AccountId, inventory: Amount) {
+		let previous = Inventory::<T>::get(market, account);
+		if previous == inventory {
+			return;
+		}
+		if inventory == 0 {
+			Inventory::<T>::remove(market, account);
+		} else {
+			Inventory::<T>::insert(market, account, inventory);
+		}
+		TotalInventory::<T>::mutate(market, |(longs, shorts)| {
+			let before = Self::balance_try_from_amount_abs(previous).unwrap_or(0);
+			if previous < 0 {
+				*shorts = shorts.saturating_sub(before);
 			} else {
-				ratio = Perquintill::from_rational(longs, shorts);
-				shorts_filled = false;
+				*longs = longs.saturating_sub(before);
 			}
-			for (account, balance) in Balances::<T>::iter() {
-				let mut amount: Amount;
-				if (balance < 0 && shorts_filled) || (balance >= 0 && !shorts_filled) {
-					amount = balance;
-				} else {
-					let b = Self::balance_try_from_amount_abs(balance).unwrap(); // TODO Panics if error
-					amount = Self::amount_try_from_balance(ratio.mul_floor(b)).unwrap(); // Should never fail given we know no overflow
-					if balance < 0 {
-						amount *= -1;
-					}
-				}
-				Inventory::<T>::insert(account, amount);
+			let after = Self::balance_try_from_amount_abs(inventory).unwrap_or(0);
+			if inventory < 0 {
+				*shorts = shorts.saturating_add(after);
+			} else {
+				*longs = longs.saturating_add(after);
+			}
+		});
+		if (inventory < 0) != (previous < 0) {
+			PnlIndex::<T>::insert(market, account, Self::pnl_index(market, inventory));
+			FundingIndex::<T>::insert(market, account, Self::funding_index(market, inventory));
+		}
+
+		let price = Price0::<T>::get(market).unwrap_or_default();
+		let entry_price = Self::entry_price_after(Self::entry_price(market, account), previous, inventory, price);
+		match entry_price {
+			Some(entry_price) => EntryPrice::<T>::insert(market, account, entry_price),
+			None => EntryPrice::<T>::remove(market, account),
+		}
+		Self::deposit_event(Event::InventoryFilled(
+			market, account.clone(), inventory.saturating_sub(previous), price, entry_price));
+	}
+
+	/// Entry price of an inventory going from `previous`, entered at `entry_price`, to `inventory`
+	/// with a fill at `price`. What is added to the inventory on the same side is averaged in,
+	/// weighted by quantity, and reducing it leaves the entry price as it is. A new inventory,
+	/// or one switching side, is entered at `price`.
+	fn entry_price_after(entry_price: Option<Price>, previous: Amount, inventory: Amount, price: Price) -> Option<Price> {
+		if inventory == 0 {
+			return None;
+		}
+		let entry_price = match entry_price {
+			Some(entry_price) if previous != 0 && (previous > 0) == (inventory > 0) => entry_price,
+			_ => return Some(price),
+		};
+		let before = Self::balance_try_from_amount_abs(previous).unwrap_or(0);
+		let after = Self::balance_try_from_amount_abs(inventory).unwrap_or(0);
+		if after <= before {
+			return Some(entry_price);
+		}
+		// Moves towards `price` by the share of the inventory added
+		let shift = |difference: Price| Price::from_inner(
+			multiply_by_rational(difference.into_inner(), after - before, after).unwrap_or(0));
+		Some(if price > entry_price {
+			entry_price.saturating_add(shift(price - entry_price))
+		} else {
+			entry_price.saturating_sub(shift(entry_price - price))
+		})
+	}
+
+	/// Fills the smaller side fully, and the crowded side one account after the other, in order of
+	/// submission for `Fifo` and from the largest interest down for `SizeWeighted`
+	fn match_by_priority(
+		market: MarketId,
+		policy: MatchingPolicy,
+		longs: Balance,
+		shorts: Balance,
+	) -> Vec<(T::AccountId, Amount)> {
+		let crowded_long = longs > shorts;
+		let mut matched: Vec<(T::AccountId, Amount)> = Vec::new();
+		let mut crowded: Vec<(T::AccountId, Balance)> = Vec::new();
+		for account in Self::interest_queue(market) {
+			let balance = Balances::<T>::get(market, &account);
+			if balance != 0 && (balance > 0) == crowded_long {
+				crowded.push((account, Self::balance_try_from_amount_abs(balance).unwrap_or(0)));
+			} else if balance != 0 {
+				matched.push((account, balance));
 			}
 		}
+		if policy == MatchingPolicy::SizeWeighted {
+			// Stable, so equal interests stay in order of submission
+			crowded.sort_by(|a, b| b.1.cmp(&a.1));
+		}
+
+		let sign: Amount = if crowded_long { 1 } else { -1 };
+		let mut left = longs.min(shorts);
+		for (account, interest) in crowded {
+			let fill = interest.min(left);
+			left -= fill;
+			matched.push((account, sign.saturating_mul(Self::amount_try_from_balance(fill).unwrap_or(0))));
+		}
+		matched
 	}
 
-	fn update_margin() {
-		// TODO: handle no price better
-		if let Some(new_price) = Self::get_price() {
-			let p0 = Price0::<T>::get().unwrap_or(new_price);
-			let multiplier;
-			let delta;
-			if new_price > p0 {
-				multiplier = 1;
-				delta = new_price - p0;
+	/// Sets the total interest of `who` to `balance`, keeping `InterestQueue` in order of submission:
+	/// an account goes to the back when it opens interest or switches side, and leaves with no interest,
+	/// getting its `PositionDeposits` back. The totals of `TotalInterest` follow, and the market is flagged
+	/// to be matched again, as well as `who` in `ChangedInterest`.
+	fn set_balance(market: MarketId, who: &T::AccountId, balance: Amount) {
+		let previous = Balances::<T>::get(market, who);
+		if previous == balance {
+			return;
+		}
+		if balance == 0 {
+			Balances::<T>::remove(market, who);
+		} else {
+			Balances::<T>::insert(market, who, balance);
+		}
+		TotalInterest::<T>::mutate(market, |(longs, shorts)| {
+			let before = Self::balance_try_from_amount_abs(previous).unwrap_or(0);
+			if previous < 0 {
+				*shorts = shorts.saturating_sub(before);
+			} else {
+				*longs = longs.saturating_sub(before);
+			}
+			let after = Self::balance_try_from_amount_abs(balance).unwrap_or(0);
+			if balance < 0 {
+				*shorts = shorts.saturating_add(after);
 			} else {
-				multiplier = -1;
-				delta = p0 - new_price;
-			}
-			Price0::<T>::set(Some(new_price));
-			if !delta.is_zero() {
-				Margin::<T>::translate(|account, margin: Balance| -> Option<Balance> {
-					let inventory = Inventory::<T>::get(account);
-					let update_inventory = delta.saturating_mul_int(inventory) * multiplier; //TODO is this a problem if it saturates?
-					// TODO panic if this fails
-					let mut amount = Self::amount_try_from_balance(margin).unwrap() + update_inventory;
-					if amount < 0 {
-						amount = 0; // No more margin left, account will be liquidated, TODO: update margin for everyone
+				*longs = longs.saturating_add(after);
+			}
+		});
+		InterestChanged::<T>::insert(market, true);
+		ChangedInterest::<T>::insert(market, who, true);
+		if balance == 0 || previous == 0 || (balance > 0) != (previous > 0) {
+			if previous != 0 {
+				Self::queue_remove(market, who);
+			}
+			if balance == 0 {
+				T::FeeCurrency::unreserve(who, PositionDeposits::<T>::take(market, who));
+			}
+			if balance != 0 {
+				Self::queue_push(market, who);
+			}
+		}
+	}
+
+	/// Adds `who` at the back of the interest queue of `market`
+	fn queue_push(market: MarketId, who: &T::AccountId) {
+		InterestQueueEnds::<T>::mutate(market, |ends| {
+			if let Some(tail) = &ends.tail {
+				InterestQueue::<T>::mutate(market, tail, |links| {
+					if let Some(links) = links {
+						links.next = Some(who.clone());
 					}
-					Some(Self::balance_try_from_amount_abs(amount).unwrap()) //TODO
 				});
 			}
+			InterestQueue::<T>::insert(market, who, QueueLinks { prev: ends.tail.clone(), next: None });
+			if ends.head.is_none() {
+				ends.head = Some(who.clone());
+			}
+			ends.tail = Some(who.clone());
+			ends.len = ends.len.saturating_add(1);
+		});
+	}
+
+	/// Takes `who` out of the interest queue of `market`, linking its neighbours together.
+	/// A liquidation cursor on `who` moves on to the account after it.
+	fn queue_remove(market: MarketId, who: &T::AccountId) {
+		let links = match InterestQueue::<T>::take(market, who) {
+			Some(links) => links,
+			None => return,
+		};
+		InterestQueueEnds::<T>::mutate(market, |ends| {
+			match &links.prev {
+				Some(prev) => InterestQueue::<T>::mutate(market, prev, |prev_links| {
+					if let Some(prev_links) = prev_links {
+						prev_links.next = links.next.clone();
+					}
+				}),
+				None => ends.head = links.next.clone(),
+			}
+			match &links.next {
+				Some(next) => InterestQueue::<T>::mutate(market, next, |next_links| {
+					if let Some(next_links) = next_links {
+						next_links.prev = links.prev.clone();
+					}
+				}),
+				None => ends.tail = links.prev.clone(),
+			}
+			ends.len = ends.len.saturating_sub(1);
+		});
+		if Self::liquidation_cursor(market).as_ref() == Some(who) {
+			Self::set_liquidation_cursor(market, links.next);
 		}
 	}
 
+	/// Gets the accounts with interest in `market`, in order of submission of their interest
+	pub fn interest_queue(market: MarketId) -> Vec<T::AccountId> {
+		let mut accounts = Vec::new();
+		let mut next = InterestQueueEnds::<T>::get(market).head;
+		while let Some(account) = next {
+			next = InterestQueue::<T>::get(market, &account).and_then(|links| links.next);
+			accounts.push(account);
+		}
+		accounts
+	}
+
+	/// Gets the number of accounts with interest in `market`, at most `MaxPositions`
+	pub fn positions(market: MarketId) -> u32 {
+		InterestQueueEnds::<T>::get(market).len
+	}
+
+	/// Marks the margins of `market` to the latest price. Returns the weight consumed.
+	fn update_margin(market: MarketId) -> Weight {
+		let weight = T::DbWeight::get().reads(2);
+		// TODO: handle no price better
+		match Self::get_price(market) {
+			Some(new_price) => weight.saturating_add(Self::mark_to_market(market, new_price)),
+			None => weight,
+		}
+	}
+
+	/// Stores `new_price` as $P_0$ and adds the move from the last one to the PnL made per unit
+	/// of `Inventory` in `market`, times the share of it which is filled as per `fill_ratios`.
+	/// Each account only realizes it when it is next settled, so this does not depend on the number
+	/// of accounts. Returns the weight consumed.
+	fn mark_to_market(market: MarketId, new_price: Price) -> Weight {
+		let p0 = Price0::<T>::get(market).unwrap_or(new_price);
+		Price0::<T>::insert(market, new_price);
+		let delta = Self::signed_price(new_price).saturating_sub(Self::signed_price(p0));
+		if delta.is_zero() {
+			return T::DbWeight::get().reads_writes(1, 1);
+		}
+		let (long_filled, short_filled) = Self::fill_ratios(market);
+		CumulativePnl::<T>::mutate(market, |(long, short)| {
+			*long = long.saturating_add(delta.saturating_mul(long_filled));
+			*short = short.saturating_sub(delta.saturating_mul(short_filled));
+		});
+		T::WeightInfo::update_margin()
+	}
+
+	/// Covers `deficit`, the losses of an account of `market` in excess of its margin.
+	/// The insurance fund pays first, and whatever it cannot pay is taken from the side
+	/// opposite to the account, which is long if `longs_lost`, pro-rata to its inventory.
+	/// This lowers the PnL made per unit of inventory of that side, so each account
+	/// pays its share when it is next settled. Returns how much is taken from that side.
+	fn cover_losses(market: MarketId, deficit: Balance, longs_lost: bool) -> Balance {
+		let remaining = deficit.saturating_sub(Self::draw_insurance_fund(market, deficit));
+		if remaining.is_zero() {
+			return 0;
+		}
+		let (longs, shorts) = Self::total_inventory(market);
+		let winners = if longs_lost { shorts } else { longs };
+		if winners.is_zero() {
+			return 0;
+		}
+		let per_unit = FixedI128::saturating_from_rational(remaining, winners);
+		CumulativePnl::<T>::mutate(market, |(long, short)| {
+			if longs_lost {
+				*short = short.saturating_sub(per_unit);
+			} else {
+				*long = long.saturating_sub(per_unit);
+			}
+		});
+		Self::deposit_event(Event::LossesDeleveraged(market, remaining));
+		remaining
+	}
+
+	/// Moves up to `amount` from the insurance fund into the module's account,
+	/// and returns how much was moved
+	fn draw_insurance_fund(market: MarketId, amount: Balance) -> Balance {
+		let drawn = amount.min(Self::insurance_fund_balance());
+		if drawn.is_zero() {
+			return 0;
+		}
+		let transferred = <T::Currency as MultiCurrency<T::AccountId>>::transfer(
+			T::NativeCurrencyId::get(),
+			&Self::insurance_fund_account(),
+			&Self::account_id(),
+			drawn);
+		if transferred.is_err() {
+			return 0;
+		}
+		Self::deposit_event(Event::InsuranceFundWithdrawn(market, drawn));
+		drawn
+	}
+
+	/// Call $N_L$ and $N_S$ the total long and short interest, and *F* the maximum funding rate.
+	/// Each funding interval, one unit of long inventory pays
+	/// $$
+	/// P_0 * F * \frac{N_L - N_S}{N_L + N_S}
+	/// $$
+	/// to the shorts (a negative amount meaning the shorts pay the longs).
+	/// Payments are only accumulated here, per unit of `Inventory` of each side as per `fill_ratios`,
+	/// and settled per account by `settle_account`.
+	fn accrue_funding(market: MarketId) {
+		if let Some(price) = Price0::<T>::get(market) {
+			let (longs, shorts) = Self::total_interest(market);
+			let total = longs.saturating_add(shorts);
+			if total.is_zero() {
+				return;
+			}
+			let mut imbalance = FixedI128::saturating_from_rational(
+				longs.max(shorts) - longs.min(shorts), total);
+			if shorts > longs {
+				imbalance = FixedI128::zero().saturating_sub(imbalance);
+			}
+			let per_unit = Self::signed_price(price)
+				.saturating_mul(FixedI128::from(T::MaxFundingRate::get()))
+				.saturating_mul(imbalance);
+			let (long_filled, short_filled) = Self::fill_ratios(market);
+			CumulativeFunding::<T>::mutate(market, |(long, short)| {
+				*long = long.saturating_add(per_unit.saturating_mul(long_filled));
+				*short = short.saturating_sub(per_unit.saturating_mul(short_filled));
+			});
+		}
+	}
+
+	/// Stores the margin of `who`, which must be settled, keeping `TotalMargin` up to date
+	fn set_margin(market: MarketId, who: &T::AccountId, margin: Balance) {
+		let previous = Margin::<T>::get(market, who);
+		Margin::<T>::insert(market, who, margin);
+		TotalMargin::<T>::mutate(|total| *total = total.saturating_add(margin).saturating_sub(previous));
+	}
+
+	/// Removes the margin of `who`, which must be settled, from storage and from `TotalMargin`
+	fn remove_margin(market: MarketId, who: &T::AccountId) {
+		let previous = Margin::<T>::take(market, who);
+		TotalMargin::<T>::mutate(|total| *total = total.saturating_sub(previous));
+	}
+
+	/// Realizes the PnL made and the funding owed on the inventory of `who` since its last
+	/// settlement into its margin, and returns the new margin. Losses in excess of the margin
+	/// are covered as per `cover_losses`.
+	fn settle_account(market: MarketId, who: &T::AccountId) -> Balance {
+		let inventory = Inventory::<T>::get(market, who);
+		let funding = Self::funding_index(market, inventory);
+		let pnl = Self::pnl_index(market, inventory);
+		let mut margin = Margin::<T>::get(market, who);
+		if funding == FundingIndex::<T>::get(market, who) && pnl == PnlIndex::<T>::get(market, who) {
+			return margin;
+		}
+		let (gain, payment) = Self::accrued(market, who, inventory);
+		if gain != 0 || payment != 0 {
+			let mut amount = Self::amount_try_from_balance(margin)
+				.unwrap_or(Amount::max_value())
+				.saturating_add(gain)
+				.saturating_sub(payment);
+			if amount < 0 {
+				// No more margin left, account will be liquidated
+				let deficit = Self::balance_try_from_amount_abs(amount).unwrap_or(0);
+				let deleveraged = Self::cover_losses(market, deficit, inventory > 0);
+				// The deficit no longer counts against the margins, but what the other side pays does
+				TotalMargin::<T>::mutate(|total| *total = total.saturating_add(deficit.saturating_sub(deleveraged)));
+				amount = 0;
+			}
+			margin = Self::balance_try_from_amount_abs(amount).unwrap_or(0);
+			Margin::<T>::insert(market, who, margin);
+			Stats::<T>::mutate(market, who, |stats| {
+				stats.realized_pnl = stats.realized_pnl.saturating_add(gain);
+				stats.funding_paid = stats.funding_paid.saturating_add(payment);
+			});
+			if payment != 0 {
+				Self::deposit_event(Event::FundingPaid(market, who.clone(), payment));
+			}
+		}
+		FundingIndex::<T>::insert(market, who, funding);
+		PnlIndex::<T>::insert(market, who, pnl);
+		margin
+	}
+
+	/// PnL made and funding owed on `inventory`, that of `who`, since its last settlement,
+	/// rounded to the nearest unit
+	fn accrued(market: MarketId, who: &T::AccountId, inventory: Amount) -> (Amount, Amount) {
+		let quantity = Self::balance_try_from_amount_abs(inventory).unwrap_or(0);
+		let gain = Self::mul_round(
+			Self::pnl_index(market, inventory).saturating_sub(PnlIndex::<T>::get(market, who)), quantity);
+		let payment = Self::mul_round(
+			Self::funding_index(market, inventory).saturating_sub(FundingIndex::<T>::get(market, who)), quantity);
+		(gain, payment)
+	}
+
+	/// `per_unit` times `quantity`, rounded to the nearest unit
+	fn mul_round(per_unit: FixedI128, quantity: Balance) -> Amount {
+		let magnitude = per_unit.into_inner().saturating_abs() as u128;
+		let accuracy = FixedI128::accuracy() as u128;
+		let floor = multiply_by_rational(magnitude, quantity, accuracy).unwrap_or(Balance::max_value());
+		// The remainder is below `accuracy` so wrapping arithmetic gets it exactly
+		let remainder = magnitude.wrapping_mul(quantity).wrapping_sub(floor.wrapping_mul(accuracy));
+		let rounded = if remainder >= accuracy / 2 { floor.saturating_add(1) } else { floor };
+		let rounded: Amount = rounded.saturated_into();
+		if per_unit.is_negative() { -rounded } else { rounded }
+	}
+
+	/// Value of `CumulativePnl` for the side of `inventory`, long when it is $0$
+	fn pnl_index(market: MarketId, inventory: Amount) -> FixedI128 {
+		let (long, short) = CumulativePnl::<T>::get(market);
+		if inventory < 0 { short } else { long }
+	}
+
+	/// Value of `CumulativeFunding` for the side of `inventory`, long when it is $0$
+	fn funding_index(market: MarketId, inventory: Amount) -> FixedI128 {
+		let (long, short) = CumulativeFunding::<T>::get(market);
+		if inventory < 0 { short } else { long }
+	}
+
+	/// Shares of the long and of the short `Inventory` of `market` which are filled by the other side:
+	/// $1$ for the smaller side and less for the crowded one. In a market matched by priority,
+	/// where both sides of `Inventory` add up to the same, both are $1$.
+	fn fill_ratios(market: MarketId) -> (FixedI128, FixedI128) {
+		let (longs, shorts) = Self::total_inventory(market);
+		let matched = longs.min(shorts);
+		let ratio = |total: Balance| if total == matched {
+			FixedI128::one()
+		} else {
+			FixedI128::saturating_from_rational(matched, total)
+		};
+		(ratio(longs), ratio(shorts))
+	}
+
+	/// Gets the inventory of `who` in `market`. Pro-rata, this is the share of its `Inventory` filled
+	/// as per `fill_ratios`, rounded down, so it follows the totals without being written.
+	pub fn inventory(market: MarketId, who: &T::AccountId) -> Amount {
+		let stored = Inventory::<T>::get(market, who);
+		let (longs, shorts) = Self::total_inventory(market);
+		let total = if stored < 0 { shorts } else { longs };
+		let matched = longs.min(shorts);
+		if stored == 0 || total == matched {
+			return stored;
+		}
+		let filled = multiply_by_rational(Self::balance_try_from_amount_abs(stored).unwrap_or(0), matched, total)
+			.ok()
+			.and_then(|filled| Self::amount_try_from_balance(filled).ok())
+			.unwrap_or(0);
+		if stored < 0 { -filled } else { filled }
+	}
+
+	/// Margin of `who` with the PnL made and the funding owed since its last settlement,
+	/// negative if it lost more than its margin
+	fn accrued_margin(market: MarketId, who: &T::AccountId) -> Amount {
+		let (gain, payment) = Self::accrued(market, who, Inventory::<T>::get(market, who));
+		Self::amount_try_from_balance(Margin::<T>::get(market, who))
+			.unwrap_or(Amount::max_value())
+			.saturating_add(gain)
+			.saturating_sub(payment)
+	}
+
+	/// Gets the margin of `who` in `market` at the last price, PnL and funding included
+	pub fn margin(market: MarketId, who: &T::AccountId) -> Balance {
+		Self::accrued_margin(market, who).max(0).saturated_into()
+	}
+
+	/// Gets the position of `who` in `market` at the last price
+	pub fn position_info(market: MarketId, who: &T::AccountId) -> PositionInfo {
+		let size = Balances::<T>::get(market, who);
+		let inventory = Self::inventory(market, who);
+		let (unrealized_pnl, _) = Self::accrued(market, who, Inventory::<T>::get(market, who));
+		PositionInfo {
+			size,
+			inventory,
+			open_interest: size.saturating_sub(inventory),
+			entry_price: Self::entry_price(market, who),
+			margin: Self::margin(market, who),
+			unrealized_pnl,
+			margin_ratio: Self::margin_ratio(market, who),
+		}
+	}
+
+	/// Gets the margin of `who` in `market` over the value of its position at the last price,
+	/// `None` without a position or a price
+	pub fn margin_ratio(market: MarketId, who: &T::AccountId) -> Option<Price> {
+		let price = Price0::<T>::get(market)?;
+		let size = Balances::<T>::get(market, who).saturating_abs();
+		Price::checked_from_rational(Self::margin(market, who), price.saturating_mul_int(size))
+	}
+
+	/// Gets the price at which the inventory of `who` in `market` would be liquidated, as per
+	/// `liquidate_account`, its margin following the price from the last one. `None` without
+	/// an inventory or a price, or for a long that cannot be liquidated.
+	pub fn liquidation_price(market: MarketId, who: &T::AccountId) -> Option<Price> {
+		let info = Self::markets(market)?;
+		let price = Price0::<T>::get(market)?;
+		let inventory = Self::inventory(market, who);
+		if inventory == 0 {
+			return None;
+		}
+		let quantity = Price::checked_from_integer(Self::balance_try_from_amount_abs(inventory).ok()?)?;
+		let margin = Price::checked_from_integer(Self::margin(market, who))?;
+		let value = price.saturating_mul(quantity);
+		let ratio = Price::from(info.liquidation_ratio);
+		if inventory > 0 {
+			// L * P * I = M + (P - P_0) * I
+			value.checked_sub(&margin)?.checked_div(&quantity.saturating_mul(Price::one().saturating_sub(ratio)))
+		} else {
+			// L * P * I = M - (P - P_0) * I
+			margin.saturating_add(value).checked_div(&quantity.saturating_mul(Price::one().saturating_add(ratio)))
+		}
+	}
+
+	/// Gets the margin `who` needs in `market` for its position to change by `delta` at the last price,
+	/// the transaction fee on `delta` included, as checked by `mint`. `None` without a price.
+	pub fn required_margin(market: MarketId, who: &T::AccountId, delta: Amount) -> Option<Balance> {
+		let info = Self::markets(market)?;
+		let price = Price0::<T>::get(market)?;
+		let balance = Balances::<T>::get(market, who).checked_add(delta)?;
+		Self::initial_margin(&info, price, balance).ok()?
+			.checked_add(Self::trading_fee(&info, price, delta).ok()?)
+	}
+
+	/// Gets the largest quantity `who` can add to its position in `market` at the last price,
+	/// on the side it is on or long without a position, once `collateral` is added to its margin.
+	/// `None` without a price.
+	pub fn max_mintable(market: MarketId, who: &T::AccountId, collateral: Amount) -> Option<Balance> {
+		Self::markets(market)?;
+		Price0::<T>::get(market)?;
+		let margin = Self::amount_try_from_balance(Self::margin(market, who)).ok()?
+			.saturating_add(collateral)
+			.max(0)
+			.saturated_into::<Balance>();
+		let sign: Amount = if Balances::<T>::get(market, who) < 0 { -1 } else { 1 };
+		let covered = |quantity: Balance| Self::amount_try_from_balance(quantity).ok()
+			.and_then(|quantity| Self::required_margin(market, who, sign.saturating_mul(quantity)))
+			.map_or(false, |needed| needed <= margin);
+		// The margin needed goes up with the quantity
+		let (mut low, mut high) = (0, Amount::max_value() as Balance);
+		while low < high {
+			let middle = low + (high - low + 1) / 2;
+			if covered(middle) {
+				low = middle;
+			} else {
+				high = middle - 1;
+			}
+		}
+		Some(low)
+	}
+
+	/// Simulates `who` minting `amount` in `market` with `collateral`, without changing storage.
+	/// The fee is taken out of the margin before it is checked against the initial margin.
+	pub fn simulate_mint(market: MarketId, who: &T::AccountId, amount: Amount, collateral: Amount) -> MintSimulation {
+		let balance = Balances::<T>::get(market, who).saturating_add(amount);
+		let (fee, required_im) = match (Self::markets(market), Price0::<T>::get(market)) {
+			(Some(info), Some(price)) => (
+				Self::trading_fee(&info, price, amount).unwrap_or_default(),
+				Self::initial_margin(&info, price, balance).unwrap_or_default(),
+			),
+			_ => (0, 0),
+		};
+		let margin = Self::amount_try_from_balance(Self::margin(market, who)).unwrap_or_default()
+			.saturating_add(collateral)
+			.saturating_sub(fee.saturated_into());
+		// Running the mint itself gives the exact error, transfers included
+		let error = with_transaction(|| TransactionOutcome::Rollback(
+			Self::do_mint(who.clone(), market, amount, collateral).err().map(|e| e.error)));
+		MintSimulation { balance, margin, fee, required_im, error }
+	}
+
+	/// Converts a price to a signed fixed point number
+	fn signed_price(price: Price) -> FixedI128 {
+		FixedI128::from_inner(price.into_inner().saturated_into())
+	}
+
 	fn account_id() -> T::AccountId {
 		T::PalletId::get().into_account()
 	}
 
+	/// The insurance fund's account, covering the losses participants cannot pay
+	fn insurance_fund_account() -> T::AccountId {
+		T::PalletId::get().into_sub_account(b"insurance")
+	}
+
+	/// Gets the balance of the insurance fund in NativeCurrency
+	pub fn insurance_fund_balance() -> Balance {
+		<T::Currency as MultiCurrency<T::AccountId>>::free_balance(T::NativeCurrencyId::get(), &Self::insurance_fund_account())
+	}
+
 	/// Gets the total balance of collateral in NativeCurrency
 	pub fn total_collateral_balance() -> Balance {
 		<T::Currency as MultiCurrency<T::AccountId>>::total_balance(T::NativeCurrencyId::get(), &Self::account_id())
 	}
 
+	/// Gets the total margin owed to participants, across all markets. PnL and funding only move
+	/// margin from one side to the other, so this is kept up to date as collateral goes in and out
+	/// of the margins, and as the deficits of accounts are settled, without going through them.
+	/// The losses of accounts not settled yet are thus counted in full, as they are taken
+	/// from the other accounts when settled.
+	pub fn total_margin() -> Balance {
+		TotalMargin::<T>::get()
+	}
+
+	/// Checks that `TotalInventory` is up to date, and that the long and short inventories of every
+	/// market matched by priority match. Pro-rata, they do by construction of `inventory`, but for
+	/// the rounding. Meant to be run from tests, after any change to the inventory.
+	pub fn do_try_state() -> Result<(), &'static str> {
+		for (market, info) in Markets::<T>::iter() {
+			let (mut longs, mut shorts) = (0u128, 0u128);
+			for (_, inventory) in Inventory::<T>::iter_prefix(market) {
+				let i = Self::balance_try_from_amount_abs(inventory).map_err(|_| "inventory overflow")?;
+				if inventory < 0 {
+					shorts = shorts.saturating_add(i);
+				} else {
+					longs = longs.saturating_add(i);
+				}
+			}
+			if longs != shorts && info.matching_policy != MatchingPolicy::ProRata {
+				return Err("long and short inventories do not match");
+			}
+			if Self::total_inventory(market) != (longs, shorts) {
+				return Err("total inventory is out of date");
+			}
+		}
+		Ok(())
+	}
+
+	/// Gets how much collateral is missing for the pool to pay back all the margins
+	pub fn shortfall() -> Balance {
+		Self::total_margin().saturating_sub(Self::total_collateral_balance())
+	}
+
+	/// Call *C* the collateral held by the pool and *M* the total margin.
+	/// If $C < M$, withdrawing *W* of margin only pays $W * C / M$, so that every
+	/// participant gets the same share of their margin back whatever the order of withdrawals.
+	fn redeemable(amount: Balance) -> Balance {
+		let total_margin = Self::total_margin();
+		let collateral = Self::total_collateral_balance();
+		if collateral >= total_margin {
+			amount
+		} else {
+			Perquintill::from_rational(collateral, total_margin).mul_floor(amount)
+		}
+	}
+
 	/// Convert `Balance` to `Amount`.
 	fn amount_try_from_balance(b: Balance) -> result::Result<Amount, Error<T>> {
 		TryInto::<Amount>::try_into(b).map_err(|_| Error::<T>::AmountConvertFailed)
@@ -414,9 +2470,10 @@ impl<T: Config> Pallet<T> {
 		TryInto::<Balance>::try_into(a.saturating_abs()).map_err(|_| Error::<T>::AmountConvertFailed)
 	}
 
-	/// Get the price from the Oracle
-	fn get_price() -> Option<Price> {
-		T::PriceSource::get_relative_price(T::NativeCurrencyId::get(), T::CurrencyId::get())
+	/// Get the price of the asset priced by `market` from the Oracle
+	fn get_price(market: MarketId) -> Option<Price> {
+		let info = Self::markets(market)?;
+		T::PriceSource::get_relative_price(T::NativeCurrencyId::get(), info.currency_id)
 	}
 }
 
@@ -23,7 +23,8 @@ use frame_support::{construct_runtime, pallet_prelude::GenesisBuild, parameter_t
 use orml_traits::parameter_type_with_key;
 use primitives::TokenSymbol;
 use sp_core::H256;
-use sp_runtime::{testing::Header, traits::{IdentityLookup, One}};
+use sp_runtime::{testing::{Header, TestXt}, traits::{IdentityLookup, One}};
+use frame_system::EnsureRoot;
 use sp_std::cell::RefCell;
 
 pub type BlockNumber = u64;
@@ -33,8 +34,12 @@ pub const ALICE: AccountId = 1;
 pub const BOB: AccountId = 2;
 pub const CHARLIE: AccountId = 3;
 pub const GEORGES: AccountId = 4;
+pub const TREASURY: AccountId = 5;
 pub const KUSD: CurrencyId = CurrencyId::Token(TokenSymbol::KUSD);
 pub const DOT: CurrencyId = CurrencyId::Token(TokenSymbol::DOT);
+pub const XBTC: CurrencyId = CurrencyId::Token(TokenSymbol::XBTC);
+pub const DOT_MARKET: MarketId = 0;
+pub const BTC_MARKET: MarketId = 1;
 
 mod perpetualasset {
 	pub use super::super::*;
@@ -44,10 +49,17 @@ parameter_types!(
 	pub const BlockHashCount: BlockNumber = 250;
 	pub const PerpetualAssetModuleId: PalletId = PalletId(*b"aca/pasm");
 	pub const NativeCurrencyId: CurrencyId = KUSD;
-	pub const UsedCurrencyId: CurrencyId = DOT;
-	pub const InitialIMRatio: Permill = Permill::from_percent(20);
-	pub const LiquidationRatio: Permill = Permill::from_percent(10);
-	pub const TransactionFee: Permill = Permill::from_parts(3000);
+	pub const FundingInterval: BlockNumber = 10;
+	pub const MaxFundingRate: Permill = Permill::from_percent(1);
+	pub const InsuranceFundShare: Permill = Permill::from_percent(50);
+	pub const LiquidatorShare: Permill = Permill::from_percent(50);
+	pub const UnsignedPriority: TransactionPriority = 1 << 20;
+	pub const UnsignedInterval: BlockNumber = 5;
+	pub const OrderDeposit: Balance = 10;
+	pub const MaxOrdersPerAccount: u32 = 2;
+	pub const MaxOrders: u32 = 4;
+	pub const MaxOrderFillsPerBlock: u32 = 2;
+	pub const PositionDeposit: Balance = 5;
 );
 
 impl frame_system::Config for Runtime {
@@ -92,8 +104,70 @@ impl orml_tokens::Config for Runtime {
 	type OnDust = ();
 }
 
+impl<LocalCall> frame_system::offchain::SendTransactionTypes<LocalCall> for Runtime where
+	Call: From<LocalCall>,
+{
+	type OverarchingCall = Call;
+	type Extrinsic = TestXt<Call, ()>;
+}
+
+pub type NativeCurrency = orml_tokens::CurrencyAdapter<Runtime, NativeCurrencyId>;
+
+pub struct MockTreasury;
+
+impl OnUnbalanced<NegativeImbalanceOf<Runtime>> for MockTreasury {
+	fn on_nonzero_unbalanced(amount: NegativeImbalanceOf<Runtime>) {
+		NativeCurrency::resolve_creating(&TREASURY, amount);
+	}
+}
+
 thread_local! {
 	static PRICE: RefCell<Option<Price>> = RefCell::new(Some(Price::one()));
+	static MAX_LIQUIDATIONS: RefCell<u32> = RefCell::new(10);
+	static MINIMUM_MARGIN: RefCell<Balance> = RefCell::new(1);
+	static MAX_POSITIONS: RefCell<u32> = RefCell::new(100);
+}
+
+pub struct MaxLiquidationsPerBlock;
+
+impl MaxLiquidationsPerBlock {
+	pub fn set(max: u32) {
+		MAX_LIQUIDATIONS.with(|v| *v.borrow_mut() = max);
+	}
+}
+
+impl Get<u32> for MaxLiquidationsPerBlock {
+	fn get() -> u32 {
+		MAX_LIQUIDATIONS.with(|v| *v.borrow())
+	}
+}
+
+pub struct MinimumMargin;
+
+impl MinimumMargin {
+	pub fn set(minimum: Balance) {
+		MINIMUM_MARGIN.with(|v| *v.borrow_mut() = minimum);
+	}
+}
+
+impl Get<Balance> for MinimumMargin {
+	fn get() -> Balance {
+		MINIMUM_MARGIN.with(|v| *v.borrow())
+	}
+}
+
+pub struct MaxPositions;
+
+impl MaxPositions {
+	pub fn set(max: u32) {
+		MAX_POSITIONS.with(|v| *v.borrow_mut() = max);
+	}
+}
+
+impl Get<u32> for MaxPositions {
+	fn get() -> u32 {
+		MAX_POSITIONS.with(|v| *v.borrow())
+	}
 }
 
 pub struct MockPriceSource;
@@ -122,12 +196,26 @@ impl perpetualasset::Config for Runtime {
 	type Event = Event;
 	type PalletId = PerpetualAssetModuleId;
 	type Currency = Tokens;
+	type FeeCurrency = NativeCurrency;
+	type Treasury = MockTreasury;
 	type NativeCurrencyId = NativeCurrencyId;
-	type CurrencyId = UsedCurrencyId;
-	type InitialIMRatio = InitialIMRatio;
-	type LiquidationRatio = LiquidationRatio;
-	type TransactionFee = TransactionFee;
+	type FundingInterval = FundingInterval;
+	type MaxFundingRate = MaxFundingRate;
 	type PriceSource = MockPriceSource;
+	type UpdateOrigin = EnsureRoot<AccountId>;
+	type InsuranceFundShare = InsuranceFundShare;
+	type LiquidatorShare = LiquidatorShare;
+	type MaxLiquidationsPerBlock = MaxLiquidationsPerBlock;
+	type UnsignedPriority = UnsignedPriority;
+	type UnsignedInterval = UnsignedInterval;
+	type MinimumMargin = MinimumMargin;
+	type OrderDeposit = OrderDeposit;
+	type MaxOrdersPerAccount = MaxOrdersPerAccount;
+	type MaxOrders = MaxOrders;
+	type MaxOrderFillsPerBlock = MaxOrderFillsPerBlock;
+	type PositionDeposit = PositionDeposit;
+	type MaxPositions = MaxPositions;
+	type WeightInfo = ();
 }
 
 type UncheckedExtrinsic = frame_system::mocking::MockUncheckedExtrinsic<Runtime>;
@@ -140,13 +228,14 @@ construct_runtime!(
 		UncheckedExtrinsic = UncheckedExtrinsic
 	{
 		System: frame_system::{Pallet, Call, Event<T>},
-		PerpetualAsset: perpetualasset::{Pallet, Call, Event<T>, Config, Storage},
+		PerpetualAsset: perpetualasset::{Pallet, Call, Event<T>, Config, Storage, ValidateUnsigned},
 		Tokens: orml_tokens::{Pallet, Storage, Event<T>, Config<T>},
 	}
 );
 
 pub struct ExtBuilder {
 	endowed_accounts: Vec<(AccountId, CurrencyId, Balance)>,
+	markets: Vec<MarketInfo>,
 }
 
 impl Default for ExtBuilder {
@@ -158,6 +247,26 @@ impl Default for ExtBuilder {
 				(CHARLIE, KUSD, 1_000_000_000_000_000_000u128),
 				(GEORGES, KUSD, 1_000_000_000_000_000_000u128),
 			],
+			markets: vec![
+				MarketInfo {
+					currency_id: DOT,
+					initial_im_ratio: Permill::from_percent(20),
+					liquidation_ratio: Permill::from_percent(10),
+					transaction_fee: Permill::zero(),
+					liquidation_penalty: Permill::from_percent(10),
+					liquidation_step: Permill::one(),
+					matching_policy: MatchingPolicy::ProRata,
+				},
+				MarketInfo {
+					currency_id: XBTC,
+					initial_im_ratio: Permill::from_percent(20),
+					liquidation_ratio: Permill::from_percent(10),
+					transaction_fee: Permill::from_parts(3000),
+					liquidation_penalty: Permill::from_percent(10),
+					liquidation_step: Permill::one(),
+					matching_policy: MatchingPolicy::ProRata,
+				},
+			],
 		}
 	}
 }
@@ -174,8 +283,10 @@ impl ExtBuilder {
 		.assimilate_storage(&mut t)
 		.unwrap();
 
-		perpetualasset::GenesisConfig::default()
-			.assimilate_storage::<Runtime>(&mut t)
+		perpetualasset::GenesisConfig {
+			markets: self.markets,
+		}
+		.assimilate_storage::<Runtime>(&mut t)
 			.unwrap();
 
 		t.into()
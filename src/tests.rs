@@ -21,7 +21,10 @@
 use super::*;
 use frame_support::{assert_noop, assert_ok};
 use mock::{Event, ExtBuilder, Origin, Runtime, PerpetualAsset, System, Tokens,
-	MockPriceSource,ALICE, BOB, CHARLIE, GEORGES, KUSD};
+	MockPriceSource, MaxLiquidationsPerBlock, MaxPositions, MinimumMargin, ALICE, BOB, CHARLIE, GEORGES, TREASURY, KUSD, XBTC, DOT_MARKET, BTC_MARKET};
+use sp_core::offchain::{testing::{TestOffchainExt, TestTransactionPoolExt}, OffchainDbExt,
+	OffchainWorkerExt, TransactionPoolExt};
+use sp_runtime::{testing::TestXt, traits::ValidateUnsigned, transaction_validity::TransactionSource};
 
 fn last_event() -> Event {
 	System::events().last().unwrap().event.clone()
@@ -34,16 +37,17 @@ fn top_up_collateral_works() {
 		System::reset_events();
 		PerpetualAsset::on_initialize(1);
 
-		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), 100i128, 21i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), DOT_MARKET, 100i128, 21i128));
 
-		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), 0i128, 10i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), DOT_MARKET, 0i128, 10i128));
 
 		assert_eq!(PerpetualAsset::total_collateral_balance(), 31u128);
-		assert_eq!(PerpetualAsset::margin(&ALICE), 30u128);
+		assert_eq!(PerpetualAsset::margin(DOT_MARKET, &ALICE), 30u128);
 
 		assert_noop!(
 			PerpetualAsset::mint(
 				Origin::signed(ALICE),
+				DOT_MARKET,
 				0i128,
 				2_000_000_000_000_000_000i128,
 			),
@@ -51,7 +55,7 @@ fn top_up_collateral_works() {
 		);
 
 		assert_eq!(PerpetualAsset::total_collateral_balance(), 31u128);
-		assert_eq!(PerpetualAsset::margin(&ALICE), 30u128);
+		assert_eq!(PerpetualAsset::margin(DOT_MARKET, &ALICE), 30u128);
 	});
 }
 
@@ -62,7 +66,7 @@ fn mint_works() {
 		System::reset_events();
 
 		assert_noop!(
-			PerpetualAsset::mint(Origin::signed(ALICE), 10i128, 1i128),
+			PerpetualAsset::mint(Origin::signed(ALICE), DOT_MARKET, 10i128, 1i128),
 			crate::Error::<Runtime>::PriceNotSet
 		);
 
@@ -71,50 +75,81 @@ fn mint_works() {
 		assert_noop!(
 			PerpetualAsset::mint(
 				Origin::signed(ALICE),
+				DOT_MARKET,
 				2_000_000_000_000_000_000i128,
 				2_000_000_000_000_000_000i128
 			),
 			orml_tokens::Error::<Runtime>::BalanceTooLow
 		);
-		assert_eq!(PerpetualAsset::margin(&ALICE), 0u128);
+		assert_eq!(PerpetualAsset::margin(DOT_MARKET, &ALICE), 0u128);
 
 		assert_noop!(
-			PerpetualAsset::mint(Origin::signed(ALICE), 10i128, 1i128),
+			PerpetualAsset::mint(Origin::signed(ALICE), DOT_MARKET, 10i128, 1i128),
 			crate::Error::<Runtime>::NotEnoughIM
 		);
 
-		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), 100i128, 20i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), DOT_MARKET, 100i128, 20i128));
 
 		assert_eq!(
 			last_event(),
-			Event::perpetualasset(crate::Event::BalanceUpdated(ALICE, 100i128))
+			Event::perpetualasset(crate::Event::BalanceUpdated(DOT_MARKET, ALICE, 100i128))
 		);
 
 		assert_eq!(PerpetualAsset::total_collateral_balance(), 20u128);
 		assert_eq!(Tokens::total_balance(KUSD, &ALICE), 999_999_999_999_999_980u128);
-		assert_eq!(PerpetualAsset::margin(&ALICE), 20u128);
+		assert_eq!(PerpetualAsset::margin(DOT_MARKET, &ALICE), 20u128);
 
-		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), -10i128, 0i128)); // Removes balance so no IM needed
+		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), DOT_MARKET, -10i128, 0i128)); // Removes balance so no IM needed
 		assert_eq!(PerpetualAsset::total_collateral_balance(), 20u128);
-		assert_eq!(PerpetualAsset::margin(&ALICE), 20u128);
-		assert_eq!(PerpetualAsset::balances(&ALICE), 90i128);
+		assert_eq!(PerpetualAsset::margin(DOT_MARKET, &ALICE), 20u128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &ALICE), 90i128);
 
-		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), 20i128, 2i128)); // Only 10 unit added, so 2 IM needed
+		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), DOT_MARKET, 20i128, 2i128)); // Only 10 unit added, so 2 IM needed
 		assert_eq!(PerpetualAsset::total_collateral_balance(), 22u128);
-		assert_eq!(PerpetualAsset::margin(&ALICE), 22u128);
+		assert_eq!(PerpetualAsset::margin(DOT_MARKET, &ALICE), 22u128);
 
 		// balance is now -200, so 40 IM needed, 22 already there, so need 18
 		assert_noop!(
-			PerpetualAsset::mint(Origin::signed(ALICE), -310i128, 17i128),
+			PerpetualAsset::mint(Origin::signed(ALICE), DOT_MARKET, -310i128, 17i128),
 			crate::Error::<Runtime>::NotEnoughIM
 		);
-		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), -310i128, 18i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), DOT_MARKET, -310i128, 18i128));
 		assert_eq!(PerpetualAsset::total_collateral_balance(), 40u128);
-		assert_eq!(PerpetualAsset::margin(&ALICE), 40u128);
+		assert_eq!(PerpetualAsset::margin(DOT_MARKET, &ALICE), 40u128);
 
-		assert_ok!(PerpetualAsset::mint(Origin::signed(BOB), -100i128, 20i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(BOB), DOT_MARKET, -100i128, 20i128));
 		assert_eq!(PerpetualAsset::total_collateral_balance(), 60u128);
-		assert_eq!(PerpetualAsset::margin(&BOB), 20u128);
+		assert_eq!(PerpetualAsset::margin(DOT_MARKET, &BOB), 20u128);
+	});
+}
+
+#[test]
+fn trade_works() {
+	ExtBuilder::default().build().execute_with(|| {
+		System::set_block_number(1);
+		System::reset_events();
+		PerpetualAsset::on_initialize(1);
+
+		assert_ok!(PerpetualAsset::trade(Origin::signed(ALICE), DOT_MARKET, Side::Short, 100, 20i128));
+		assert_eq!(
+			last_event(),
+			Event::perpetualasset(crate::Event::BalanceUpdated(DOT_MARKET, ALICE, -100i128))
+		);
+		assert_eq!(PerpetualAsset::margin(DOT_MARKET, &ALICE), 20u128);
+
+		// A long trade reduces the short interest, so some margin can come out
+		assert_ok!(PerpetualAsset::trade(Origin::signed(ALICE), DOT_MARKET, Side::Long, 50, -10i128));
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &ALICE), -50i128);
+		assert_eq!(PerpetualAsset::margin(DOT_MARKET, &ALICE), 10u128);
+
+		assert_noop!(
+			PerpetualAsset::trade(Origin::signed(ALICE), DOT_MARKET, Side::Short, 10, -1i128),
+			crate::Error::<Runtime>::NotEnoughIM
+		);
+		assert_noop!(
+			PerpetualAsset::trade(Origin::signed(ALICE), DOT_MARKET, Side::Long, u128::MAX, 0i128),
+			crate::Error::<Runtime>::AmountConvertFailed
+		);
 	});
 }
 
@@ -126,31 +161,32 @@ fn match_interest_works() {
 
 		PerpetualAsset::on_initialize(1);
 
-		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), 100i128, 20i128));
-		assert_ok!(PerpetualAsset::mint(Origin::signed(BOB), -100i128, 20i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), DOT_MARKET, 100i128, 20i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(BOB), DOT_MARKET, -100i128, 20i128));
 
 		PerpetualAsset::on_initialize(2);
 
-		assert_eq!(PerpetualAsset::inventory(&ALICE), 100i128);
-		assert_eq!(PerpetualAsset::inventory(&BOB), -100i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &ALICE), 100i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &BOB), -100i128);
 
-		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), -50i128, 0i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), DOT_MARKET, -50i128, 0i128));
 		PerpetualAsset::on_initialize(3);
-		assert_eq!(PerpetualAsset::inventory(&ALICE), 50i128);
-		assert_eq!(PerpetualAsset::inventory(&BOB), -50i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &ALICE), 50i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &BOB), -50i128);
 
-		assert_ok!(PerpetualAsset::mint(Origin::signed(CHARLIE), 100i128, 20i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(CHARLIE), DOT_MARKET, 100i128, 20i128));
 		PerpetualAsset::on_initialize(4);
-		assert_eq!(PerpetualAsset::inventory(&ALICE), 33i128);
-		assert_eq!(PerpetualAsset::inventory(&CHARLIE), 66i128);
-		assert_eq!(PerpetualAsset::inventory(&BOB), -100i128);
+		// The inventories are rounded down
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &ALICE), 33i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &CHARLIE), 66i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &BOB), -100i128);
 
-		assert_ok!(PerpetualAsset::mint(Origin::signed(GEORGES), -100i128, 20i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(GEORGES), DOT_MARKET, -100i128, 20i128));
 		PerpetualAsset::on_initialize(4);
-		assert_eq!(PerpetualAsset::inventory(&ALICE), 50i128);
-		assert_eq!(PerpetualAsset::inventory(&CHARLIE), 100i128);
-		assert_eq!(PerpetualAsset::inventory(&BOB), -75i128);
-		assert_eq!(PerpetualAsset::inventory(&GEORGES), -75i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &ALICE), 50i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &CHARLIE), 100i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &BOB), -75i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &GEORGES), -75i128);
 	});
 }
 
@@ -161,97 +197,246 @@ fn redeem_works() {
 		System::reset_events();
 		PerpetualAsset::on_initialize(1);
 
-		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), 100i128, 20i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), DOT_MARKET, 100i128, 20i128));
 		assert_eq!(PerpetualAsset::total_collateral_balance(), 20u128);
-		assert_eq!(PerpetualAsset::margin(&ALICE), 20u128);
-		assert_eq!(PerpetualAsset::balances(&ALICE), 100i128);
+		assert_eq!(PerpetualAsset::margin(DOT_MARKET, &ALICE), 20u128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &ALICE), 100i128);
 
 		assert_noop!(
-			PerpetualAsset::mint(Origin::signed(ALICE), 0i128, -1i128),
+			PerpetualAsset::mint(Origin::signed(ALICE), DOT_MARKET, 0i128, -1i128),
 			crate::Error::<Runtime>::NotEnoughIM
 		);
 
 		assert_eq!(PerpetualAsset::total_collateral_balance(), 20u128);
-		assert_eq!(PerpetualAsset::margin(&ALICE), 20u128);
-		assert_eq!(PerpetualAsset::balances(&ALICE), 100i128);
+		assert_eq!(PerpetualAsset::margin(DOT_MARKET, &ALICE), 20u128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &ALICE), 100i128);
 
-		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), 100i128, 60i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), DOT_MARKET, 100i128, 60i128));
 		assert_eq!(PerpetualAsset::total_collateral_balance(), 80u128);
-		assert_eq!(PerpetualAsset::margin(&ALICE), 80u128);
-		assert_eq!(PerpetualAsset::balances(&ALICE), 200i128);
+		assert_eq!(PerpetualAsset::margin(DOT_MARKET, &ALICE), 80u128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &ALICE), 200i128);
 
-		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), 100i128, -10i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), DOT_MARKET, 100i128, -10i128));
 		assert_eq!(PerpetualAsset::total_collateral_balance(), 70u128);
-		assert_eq!(PerpetualAsset::margin(&ALICE), 70u128);
-		assert_eq!(PerpetualAsset::balances(&ALICE), 300i128);
+		assert_eq!(PerpetualAsset::margin(DOT_MARKET, &ALICE), 70u128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &ALICE), 300i128);
 
-		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), 100i128, 10i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), DOT_MARKET, 100i128, 10i128));
 		assert_eq!(PerpetualAsset::total_collateral_balance(), 80u128);
-		assert_eq!(PerpetualAsset::margin(&ALICE), 80u128);
-		assert_eq!(PerpetualAsset::balances(&ALICE), 400i128);
+		assert_eq!(PerpetualAsset::margin(DOT_MARKET, &ALICE), 80u128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &ALICE), 400i128);
 
-		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), 0i128, 10i128));
-		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), 100i128, 10i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), DOT_MARKET, 0i128, 10i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), DOT_MARKET, 100i128, 10i128));
 
 		assert_noop!(
-			PerpetualAsset::mint(Origin::signed(ALICE), 100i128, 10i128),
+			PerpetualAsset::mint(Origin::signed(ALICE), DOT_MARKET, 100i128, 10i128),
 			crate::Error::<Runtime>::NotEnoughIM
 		);
 	});
 }
 
 #[test]
-fn liquidate_works() {
+fn deposit_and_withdraw_margin_works() {
 	ExtBuilder::default().build().execute_with(|| {
 		System::set_block_number(1);
 		System::reset_events();
 		PerpetualAsset::on_initialize(1);
 
-		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), 100i128, 20i128));
-		assert_ok!(PerpetualAsset::mint(Origin::signed(BOB), -100i128, 20i128));
-		assert_ok!(PerpetualAsset::mint(Origin::signed(CHARLIE), 50i128, 20i128));
-		assert_ok!(PerpetualAsset::mint(Origin::signed(GEORGES), -10i128, 20i128));
-		PerpetualAsset::on_initialize(2);
+		assert_ok!(PerpetualAsset::deposit_margin(Origin::signed(ALICE), DOT_MARKET, 50));
+		assert_eq!(last_event(), Event::perpetualasset(crate::Event::MarginDeposited(DOT_MARKET, ALICE, 50)));
+		assert_eq!(PerpetualAsset::margin(DOT_MARKET, &ALICE), 50u128);
+		assert_eq!(PerpetualAsset::total_collateral_balance(), 50u128);
 
-		assert_eq!(PerpetualAsset::inventory(&ALICE), 73i128);
-		assert_eq!(PerpetualAsset::inventory(&BOB), -100i128);
-		assert_eq!(PerpetualAsset::inventory(&CHARLIE), 36i128);
-		assert_eq!(PerpetualAsset::inventory(&GEORGES), -10i128);
-		assert_eq!(PerpetualAsset::balances(&ALICE), 100i128);
-		assert_eq!(PerpetualAsset::balances(&BOB), -100i128);
-		assert_eq!(PerpetualAsset::balances(&CHARLIE), 50i128);
-		assert_eq!(PerpetualAsset::balances(&GEORGES), -10i128);
+		// The margin covers a position without sending more collateral
+		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), DOT_MARKET, 100i128, 0i128));
+		assert_noop!(
+			PerpetualAsset::withdraw_margin(Origin::signed(ALICE), DOT_MARKET, 31),
+			crate::Error::<Runtime>::NotEnoughIM
+		);
+		assert_ok!(PerpetualAsset::withdraw_margin(Origin::signed(ALICE), DOT_MARKET, 10));
+		assert_eq!(last_event(), Event::perpetualasset(crate::Event::MarginWithdrawn(DOT_MARKET, ALICE, 10)));
+		assert_eq!(PerpetualAsset::margin(DOT_MARKET, &ALICE), 40u128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &ALICE), 100i128);
+
+		// Only the margin above the IM is withdrawn
+		assert_ok!(PerpetualAsset::withdraw_all(Origin::signed(ALICE), DOT_MARKET));
+		assert_eq!(last_event(), Event::perpetualasset(crate::Event::MarginWithdrawn(DOT_MARKET, ALICE, 20)));
+		assert_eq!(PerpetualAsset::margin(DOT_MARKET, &ALICE), 20u128);
+		assert_eq!(PerpetualAsset::total_collateral_balance(), 20u128);
 
-		MockPriceSource::set_price(Some(2u128.into()));
-		PerpetualAsset::update_margin();
+		assert_noop!(
+			PerpetualAsset::deposit_margin(Origin::signed(ALICE), 2, 50),
+			crate::Error::<Runtime>::MarketNotFound
+		);
+	});
+}
 
-		assert_eq!(PerpetualAsset::total_collateral_balance(), 80u128);
-		assert_eq!(PerpetualAsset::margin(&ALICE), 93u128);
-		assert_eq!(PerpetualAsset::margin(&BOB), 0u128);
-		assert_eq!(PerpetualAsset::margin(&CHARLIE), 56u128);
-		assert_eq!(PerpetualAsset::margin(&GEORGES), 10u128);
+#[test]
+fn close_position_works() {
+	ExtBuilder::default().build().execute_with(|| {
+		System::set_block_number(1);
+		System::reset_events();
+		PerpetualAsset::on_initialize(1);
+
+		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), DOT_MARKET, 100i128, 20i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(BOB), DOT_MARKET, -100i128, 20i128));
+		PerpetualAsset::match_interest(DOT_MARKET);
+
+		// The short inventory of BOB goes back to being open interest
+		assert_ok!(PerpetualAsset::close_position(Origin::signed(ALICE), DOT_MARKET));
+		assert_eq!(
+			last_event(),
+			Event::perpetualasset(crate::Event::PositionClosed(DOT_MARKET, ALICE, 100i128, 20u128))
+		);
+		assert_eq!(Tokens::total_balance(KUSD, &ALICE), 1_000_000_000_000_000_000u128);
+		assert!(!Balances::<Runtime>::contains_key(DOT_MARKET, ALICE));
+		assert!(!Margin::<Runtime>::contains_key(DOT_MARKET, ALICE));
+		assert!(!Inventory::<Runtime>::contains_key(DOT_MARKET, ALICE));
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &BOB), 0i128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &BOB), -100i128);
+		assert_ok!(PerpetualAsset::do_try_state());
+
+		assert_noop!(
+			PerpetualAsset::close_position(Origin::signed(ALICE), DOT_MARKET),
+			crate::Error::<Runtime>::NothingToClose
+		);
+
+		// The fee on the position is paid out of the margin
+		assert_ok!(PerpetualAsset::mint(Origin::signed(BOB), BTC_MARKET, -100i128, 20i128));
+		assert_eq!(PerpetualAsset::margin(BTC_MARKET, &BOB), 19u128);
+		assert_ok!(PerpetualAsset::close_position(Origin::signed(BOB), BTC_MARKET));
+		assert_eq!(
+			last_event(),
+			Event::perpetualasset(crate::Event::PositionClosed(BTC_MARKET, BOB, -100i128, 18u128))
+		);
+		assert_eq!(PerpetualAsset::balances(BTC_MARKET, &BOB), 0i128);
+	});
+}
+
+#[test]
+fn dust_accounts_are_reaped() {
+	ExtBuilder::default().build().execute_with(|| {
+		System::set_block_number(1);
+		System::reset_events();
+		PerpetualAsset::on_initialize(1);
+		MinimumMargin::set(5);
+
+		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), DOT_MARKET, 100i128, 20i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), DOT_MARKET, -100i128, -17i128));
+		assert_eq!(
+			last_event(),
+			Event::perpetualasset(crate::Event::AccountReaped(DOT_MARKET, ALICE, 3u128))
+		);
+		assert!(!Balances::<Runtime>::contains_key(DOT_MARKET, ALICE));
+		assert!(!Margin::<Runtime>::contains_key(DOT_MARKET, ALICE));
+		assert_eq!(PerpetualAsset::interest_queue(DOT_MARKET), vec![]);
+		assert_eq!(Tokens::total_balance(KUSD, &TREASURY), 3u128);
+		assert_eq!(PerpetualAsset::total_collateral_balance(), 0u128);
+
+		// An account with nothing left is reaped too
+		assert_ok!(PerpetualAsset::deposit_margin(Origin::signed(BOB), DOT_MARKET, 10));
+		assert_ok!(PerpetualAsset::withdraw_margin(Origin::signed(BOB), DOT_MARKET, 10));
+		assert_eq!(
+			last_event(),
+			Event::perpetualasset(crate::Event::AccountReaped(DOT_MARKET, BOB, 0u128))
+		);
+
+		// Margin above the minimum stays
+		assert_ok!(PerpetualAsset::deposit_margin(Origin::signed(CHARLIE), DOT_MARKET, 10));
+		assert_ok!(PerpetualAsset::withdraw_margin(Origin::signed(CHARLIE), DOT_MARKET, 5));
+		assert_eq!(PerpetualAsset::margin(DOT_MARKET, &CHARLIE), 5u128);
+		assert_ok!(PerpetualAsset::withdraw_margin(Origin::signed(CHARLIE), DOT_MARKET, 1));
+		assert!(!Margin::<Runtime>::contains_key(DOT_MARKET, CHARLIE));
+		assert_eq!(Tokens::total_balance(KUSD, &TREASURY), 7u128);
+
+		// Deposits too small to be kept are refused rather than swept to the treasury
+		assert_noop!(
+			PerpetualAsset::mint(Origin::signed(GEORGES), DOT_MARKET, 0i128, 4i128),
+			crate::Error::<Runtime>::BelowMinimumMargin
+		);
+		assert_noop!(
+			PerpetualAsset::deposit_margin(Origin::signed(GEORGES), DOT_MARKET, 4),
+			crate::Error::<Runtime>::BelowMinimumMargin
+		);
+		assert_ok!(PerpetualAsset::deposit_margin(Origin::signed(GEORGES), DOT_MARKET, 5));
+		assert_eq!(PerpetualAsset::margin(DOT_MARKET, &GEORGES), 5u128);
+
+		// A close leaves the inventory to the next match, which reaps the account
+		assert_ok!(PerpetualAsset::mint(Origin::signed(CHARLIE), DOT_MARKET, 100i128, 20i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(BOB), DOT_MARKET, -100i128, 20i128));
+		PerpetualAsset::match_interest(DOT_MARKET);
+		assert_ok!(PerpetualAsset::mint(Origin::signed(CHARLIE), DOT_MARKET, -100i128, -17i128));
+		assert!(Margin::<Runtime>::contains_key(DOT_MARKET, CHARLIE));
+		System::set_block_number(2);
+		PerpetualAsset::match_interest(DOT_MARKET);
+		assert_eq!(
+			last_event(),
+			Event::perpetualasset(crate::Event::AccountReaped(DOT_MARKET, CHARLIE, 3u128))
+		);
+		assert!(!Inventory::<Runtime>::contains_key(DOT_MARKET, CHARLIE));
+		assert!(!Margin::<Runtime>::contains_key(DOT_MARKET, CHARLIE));
+		assert!(!Stats::<Runtime>::contains_key(DOT_MARKET, CHARLIE));
+		assert_eq!(Tokens::total_balance(KUSD, &TREASURY), 10u128);
+	});
+}
+
+#[test]
+fn liquidate_works() {
+	ExtBuilder::default().build().execute_with(|| {
+		System::set_block_number(1);
+		System::reset_events();
+		PerpetualAsset::on_initialize(1);
 
-		PerpetualAsset::liquidate();
+		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), DOT_MARKET, 100i128, 20i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(BOB), DOT_MARKET, -100i128, 20i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(CHARLIE), DOT_MARKET, 50i128, 20i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(GEORGES), DOT_MARKET, -10i128, 20i128));
+		PerpetualAsset::on_initialize(2);
 
-		assert_eq!(PerpetualAsset::inventory(&ALICE), 73i128);
-		assert_eq!(PerpetualAsset::inventory(&BOB), 0i128);
-		assert_eq!(PerpetualAsset::inventory(&CHARLIE), 36i128);
-		assert_eq!(PerpetualAsset::inventory(&GEORGES), -10i128);
-		assert_eq!(PerpetualAsset::balances(&ALICE), 100i128);
-		assert_eq!(PerpetualAsset::balances(&BOB), 0i128);
-		assert_eq!(PerpetualAsset::balances(&CHARLIE), 50i128);
-		assert_eq!(PerpetualAsset::balances(&GEORGES), -10i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &ALICE), 73i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &BOB), -100i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &CHARLIE), 36i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &GEORGES), -10i128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &ALICE), 100i128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &BOB), -100i128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &CHARLIE), 50i128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &GEORGES), -10i128);
 
-		PerpetualAsset::match_interest();
+		MockPriceSource::set_price(Some(2u128.into()));
+		PerpetualAsset::update_margin(DOT_MARKET);
+		PerpetualAsset::liquidate_accounts(DOT_MARKET);
 
-		assert_eq!(PerpetualAsset::inventory(&ALICE), 6i128);
-		assert_eq!(PerpetualAsset::inventory(&BOB), 0i128);
-		assert_eq!(PerpetualAsset::inventory(&CHARLIE), 3i128);
-		assert_eq!(PerpetualAsset::inventory(&GEORGES), -10i128);
-		assert_eq!(PerpetualAsset::balances(&ALICE), 100i128);
-		assert_eq!(PerpetualAsset::balances(&BOB), 0i128);
-		assert_eq!(PerpetualAsset::balances(&CHARLIE), 50i128);
-		assert_eq!(PerpetualAsset::balances(&GEORGES), -10i128);
+		// BOB lost 80 more than his margin, ALICE and CHARLIE pay for it pro-rata
+		assert!(System::events().iter().any(|r| r.event ==
+			Event::perpetualasset(crate::Event::LossesDeleveraged(DOT_MARKET, 80u128))));
+		assert_eq!(PerpetualAsset::total_collateral_balance(), 80u128);
+		assert_eq!(PerpetualAsset::margin(DOT_MARKET, &ALICE), 40u128);
+		assert_eq!(PerpetualAsset::margin(DOT_MARKET, &BOB), 0u128);
+		assert_eq!(PerpetualAsset::margin(DOT_MARKET, &CHARLIE), 30u128);
+		assert_eq!(PerpetualAsset::margin(DOT_MARKET, &GEORGES), 10u128);
+		assert_eq!(PerpetualAsset::shortfall(), 0u128);
+
+		// No other short has open interest, so BOB's inventory is removed from ALICE and CHARLIE
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &ALICE), 6i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &BOB), 0i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &CHARLIE), 3i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &GEORGES), -10i128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &ALICE), 100i128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &BOB), 0i128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &CHARLIE), 50i128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &GEORGES), -10i128);
+
+		PerpetualAsset::match_interest(DOT_MARKET);
+
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &ALICE), 6i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &BOB), 0i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &CHARLIE), 3i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &GEORGES), -10i128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &ALICE), 100i128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &BOB), 0i128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &CHARLIE), 50i128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &GEORGES), -10i128);
 	});
 }
 
@@ -261,29 +446,30 @@ fn liquidate_works_0_price() {
 		System::set_block_number(1);
 		System::reset_events();
 		MockPriceSource::set_price(Some(20u128.into()));
-		PerpetualAsset::update_margin();
+		PerpetualAsset::update_margin(DOT_MARKET);
 
-		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), 100i128, 400i128));
-		assert_ok!(PerpetualAsset::mint(Origin::signed(BOB), -100i128, 400i128));
-		PerpetualAsset::match_interest();
+		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), DOT_MARKET, 100i128, 400i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(BOB), DOT_MARKET, -100i128, 400i128));
+		PerpetualAsset::match_interest(DOT_MARKET);
 
-		assert_eq!(PerpetualAsset::inventory(&ALICE), 100i128);
-		assert_eq!(PerpetualAsset::inventory(&BOB), -100i128);
-		assert_eq!(PerpetualAsset::balances(&ALICE), 100i128);
-		assert_eq!(PerpetualAsset::balances(&BOB), -100i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &ALICE), 100i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &BOB), -100i128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &ALICE), 100i128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &BOB), -100i128);
 
 		// Price goes to 0, ALICE should be fully liquidated
 		MockPriceSource::set_price(Some(0u128.into()));
-		PerpetualAsset::update_margin();
-		PerpetualAsset::liquidate();
+		PerpetualAsset::update_margin(DOT_MARKET);
+		PerpetualAsset::liquidate_accounts(DOT_MARKET);
 		assert_eq!(PerpetualAsset::total_collateral_balance(), 800u128);
-		assert_eq!(PerpetualAsset::margin(&ALICE), 0u128);
-		assert_eq!(PerpetualAsset::margin(&BOB), 2400u128);
-
-		assert_eq!(PerpetualAsset::inventory(&ALICE), 0i128);
-		assert_eq!(PerpetualAsset::inventory(&BOB), -100i128);
-		assert_eq!(PerpetualAsset::balances(&ALICE), 0i128);
-		assert_eq!(PerpetualAsset::balances(&BOB), -100i128);
+		assert_eq!(PerpetualAsset::margin(DOT_MARKET, &ALICE), 0u128);
+		assert_eq!(PerpetualAsset::margin(DOT_MARKET, &BOB), 800u128);
+
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &ALICE), 0i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &BOB), 0i128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &ALICE), 0i128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &BOB), -100i128);
+		assert_ok!(PerpetualAsset::do_try_state());
 	});
 }
 
@@ -293,51 +479,51 @@ fn liquidate_works_complex_2() {
 		System::set_block_number(1);
 		System::reset_events();
 		MockPriceSource::set_price(Some(20u128.into()));
-		PerpetualAsset::update_margin();
-
-		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), 100i128, 400i128));
-		assert_ok!(PerpetualAsset::mint(Origin::signed(BOB), -100i128, 400i128));
-		assert_ok!(PerpetualAsset::mint(Origin::signed(CHARLIE), 100i128, 4000i128));
-		assert_ok!(PerpetualAsset::mint(Origin::signed(GEORGES), 100i128, 4000i128));
-		PerpetualAsset::match_interest();
-
-		assert_eq!(PerpetualAsset::inventory(&ALICE), 33i128);
-		assert_eq!(PerpetualAsset::inventory(&BOB), -100i128);
-		assert_eq!(PerpetualAsset::inventory(&CHARLIE), 33i128);
-		assert_eq!(PerpetualAsset::inventory(&GEORGES), 33i128);
-		assert_eq!(PerpetualAsset::balances(&ALICE), 100i128);
-		assert_eq!(PerpetualAsset::balances(&BOB), -100i128);
-		assert_eq!(PerpetualAsset::balances(&CHARLIE), 100i128);
-		assert_eq!(PerpetualAsset::balances(&GEORGES), 100i128);
+		PerpetualAsset::update_margin(DOT_MARKET);
+
+		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), DOT_MARKET, 100i128, 410i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(BOB), DOT_MARKET, -100i128, 400i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(CHARLIE), DOT_MARKET, 100i128, 4000i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(GEORGES), DOT_MARKET, 100i128, 4000i128));
+		PerpetualAsset::match_interest(DOT_MARKET);
+
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &ALICE), 33i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &BOB), -100i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &CHARLIE), 33i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &GEORGES), 33i128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &ALICE), 100i128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &BOB), -100i128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &CHARLIE), 100i128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &GEORGES), 100i128);
 
 		// liquidate all of Alice's open interest
 		MockPriceSource::set_price(Some(9u128.into()));
-		PerpetualAsset::update_margin();
-		PerpetualAsset::liquidate();
-		assert_eq!(PerpetualAsset::total_collateral_balance(), 8800u128);
-		assert_eq!(PerpetualAsset::margin(&ALICE), 37u128);
-		assert_eq!(PerpetualAsset::margin(&BOB), 1500u128);
-		assert_eq!(PerpetualAsset::margin(&CHARLIE), 3637u128);
-		assert_eq!(PerpetualAsset::margin(&GEORGES), 3637u128);
-
-		assert_eq!(PerpetualAsset::inventory(&ALICE), 33i128);
-		assert_eq!(PerpetualAsset::inventory(&BOB), -100i128);
-		assert_eq!(PerpetualAsset::inventory(&CHARLIE), 33i128);
-		assert_eq!(PerpetualAsset::inventory(&GEORGES), 33i128);
-		assert_eq!(PerpetualAsset::balances(&ALICE), 33i128);
-		assert_eq!(PerpetualAsset::balances(&BOB), -100i128);
-		assert_eq!(PerpetualAsset::balances(&CHARLIE), 100i128);
-		assert_eq!(PerpetualAsset::balances(&GEORGES), 100i128);
-
-		PerpetualAsset::match_interest();
-		assert_eq!(PerpetualAsset::inventory(&ALICE), 14i128);
-		assert_eq!(PerpetualAsset::inventory(&BOB), -100i128);
-		assert_eq!(PerpetualAsset::inventory(&CHARLIE), 42i128);
-		assert_eq!(PerpetualAsset::inventory(&GEORGES), 42i128);
-		assert_eq!(PerpetualAsset::balances(&ALICE), 33);
-		assert_eq!(PerpetualAsset::balances(&BOB), -100i128);
-		assert_eq!(PerpetualAsset::balances(&CHARLIE), 100i128);
-		assert_eq!(PerpetualAsset::balances(&GEORGES), 100i128);
+		PerpetualAsset::update_margin(DOT_MARKET);
+		PerpetualAsset::liquidate_accounts(DOT_MARKET);
+		assert_eq!(PerpetualAsset::total_collateral_balance(), 8810u128);
+		assert_eq!(PerpetualAsset::margin(DOT_MARKET, &ALICE), 43u128);
+		assert_eq!(PerpetualAsset::margin(DOT_MARKET, &BOB), 1500u128);
+		assert_eq!(PerpetualAsset::margin(DOT_MARKET, &CHARLIE), 3633u128);
+		assert_eq!(PerpetualAsset::margin(DOT_MARKET, &GEORGES), 3633u128);
+
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &ALICE), 33i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &BOB), -100i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &CHARLIE), 33i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &GEORGES), 33i128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &ALICE), 33i128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &BOB), -100i128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &CHARLIE), 100i128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &GEORGES), 100i128);
+
+		PerpetualAsset::match_interest(DOT_MARKET);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &ALICE), 14i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &BOB), -100i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &CHARLIE), 42i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &GEORGES), 42i128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &ALICE), 33);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &BOB), -100i128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &CHARLIE), 100i128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &GEORGES), 100i128);
 	});
 }
 
@@ -347,64 +533,64 @@ fn liquidate_works_complex() {
 		System::set_block_number(1);
 		System::reset_events();
 		MockPriceSource::set_price(Some(20u128.into()));
-		PerpetualAsset::update_margin();
-
-		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), 100i128, 450i128));
-		assert_ok!(PerpetualAsset::mint(Origin::signed(BOB), -100i128, 400i128));
-		assert_ok!(PerpetualAsset::mint(Origin::signed(CHARLIE), 50i128, 400i128));
-		assert_ok!(PerpetualAsset::mint(Origin::signed(GEORGES), -10i128, 400i128));
-		PerpetualAsset::match_interest();
-
-		assert_eq!(PerpetualAsset::inventory(&ALICE), 73i128);
-		assert_eq!(PerpetualAsset::inventory(&BOB), -100i128);
-		assert_eq!(PerpetualAsset::inventory(&CHARLIE), 36i128);
-		assert_eq!(PerpetualAsset::inventory(&GEORGES), -10i128);
-		assert_eq!(PerpetualAsset::balances(&ALICE), 100i128);
-		assert_eq!(PerpetualAsset::balances(&BOB), -100i128);
-		assert_eq!(PerpetualAsset::balances(&CHARLIE), 50i128);
-		assert_eq!(PerpetualAsset::balances(&GEORGES), -10i128);
+		PerpetualAsset::update_margin(DOT_MARKET);
+
+		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), DOT_MARKET, 100i128, 450i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(BOB), DOT_MARKET, -100i128, 400i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(CHARLIE), DOT_MARKET, 50i128, 400i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(GEORGES), DOT_MARKET, -10i128, 400i128));
+		PerpetualAsset::match_interest(DOT_MARKET);
+
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &ALICE), 73i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &BOB), -100i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &CHARLIE), 36i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &GEORGES), -10i128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &ALICE), 100i128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &BOB), -100i128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &CHARLIE), 50i128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &GEORGES), -10i128);
 
 		// No liquidation
 		MockPriceSource::set_price(Some(19u128.into()));
-		PerpetualAsset::update_margin();
-		PerpetualAsset::liquidate();
-		assert_eq!(PerpetualAsset::inventory(&ALICE), 73i128);
-		assert_eq!(PerpetualAsset::inventory(&BOB), -100i128);
-		assert_eq!(PerpetualAsset::inventory(&CHARLIE), 36i128);
-		assert_eq!(PerpetualAsset::inventory(&GEORGES), -10i128);
-		assert_eq!(PerpetualAsset::balances(&ALICE), 100i128);
-		assert_eq!(PerpetualAsset::balances(&BOB), -100i128);
-		assert_eq!(PerpetualAsset::balances(&CHARLIE), 50i128);
-		assert_eq!(PerpetualAsset::balances(&GEORGES), -10i128);
+		PerpetualAsset::update_margin(DOT_MARKET);
+		PerpetualAsset::liquidate_accounts(DOT_MARKET);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &ALICE), 73i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &BOB), -100i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &CHARLIE), 36i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &GEORGES), -10i128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &ALICE), 100i128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &BOB), -100i128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &CHARLIE), 50i128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &GEORGES), -10i128);
 
 		// liquidate Alice's open interest only
 		MockPriceSource::set_price(Some(16u128.into()));
-		PerpetualAsset::update_margin();
-		PerpetualAsset::liquidate();
+		PerpetualAsset::update_margin(DOT_MARKET);
+		PerpetualAsset::liquidate_accounts(DOT_MARKET);
 		assert_eq!(PerpetualAsset::total_collateral_balance(), 1650u128);
-		assert_eq!(PerpetualAsset::margin(&ALICE), 158u128);
-		assert_eq!(PerpetualAsset::margin(&BOB), 800u128);
-		assert_eq!(PerpetualAsset::margin(&CHARLIE), 256u128);
-		assert_eq!(PerpetualAsset::margin(&GEORGES), 440u128);
-
-		assert_eq!(PerpetualAsset::inventory(&ALICE), 73i128);
-		assert_eq!(PerpetualAsset::inventory(&BOB), -100i128);
-		assert_eq!(PerpetualAsset::inventory(&CHARLIE), 36i128);
-		assert_eq!(PerpetualAsset::inventory(&GEORGES), -10i128);
-		assert_eq!(PerpetualAsset::balances(&ALICE), 73i128);
-		assert_eq!(PerpetualAsset::balances(&BOB), -100i128);
-		assert_eq!(PerpetualAsset::balances(&CHARLIE), 50i128);
-		assert_eq!(PerpetualAsset::balances(&GEORGES), -10i128);
-
-		PerpetualAsset::match_interest();
-		assert_eq!(PerpetualAsset::inventory(&ALICE), 65i128);
-		assert_eq!(PerpetualAsset::inventory(&BOB), -100i128);
-		assert_eq!(PerpetualAsset::inventory(&CHARLIE), 44i128);
-		assert_eq!(PerpetualAsset::inventory(&GEORGES), -10i128);
-		assert_eq!(PerpetualAsset::balances(&ALICE), 73i128);
-		assert_eq!(PerpetualAsset::balances(&BOB), -100i128);
-		assert_eq!(PerpetualAsset::balances(&CHARLIE), 50i128);
-		assert_eq!(PerpetualAsset::balances(&GEORGES), -10i128);
+		assert_eq!(PerpetualAsset::margin(DOT_MARKET, &ALICE), 157u128);
+		assert_eq!(PerpetualAsset::margin(DOT_MARKET, &BOB), 800u128);
+		assert_eq!(PerpetualAsset::margin(DOT_MARKET, &CHARLIE), 253u128);
+		assert_eq!(PerpetualAsset::margin(DOT_MARKET, &GEORGES), 440u128);
+
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &ALICE), 73i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &BOB), -100i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &CHARLIE), 36i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &GEORGES), -10i128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &ALICE), 73i128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &BOB), -100i128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &CHARLIE), 50i128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &GEORGES), -10i128);
+
+		PerpetualAsset::match_interest(DOT_MARKET);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &ALICE), 65i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &BOB), -100i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &CHARLIE), 44i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &GEORGES), -10i128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &ALICE), 73i128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &BOB), -100i128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &CHARLIE), 50i128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &GEORGES), -10i128);
 	});
 }
 
@@ -415,32 +601,38 @@ fn update_balances_works() {
 		System::reset_events();
 		PerpetualAsset::on_initialize(1);
 
-		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), 100i128, 20i128));
-		assert_ok!(PerpetualAsset::mint(Origin::signed(BOB), -100i128, 20i128));
-		assert_ok!(PerpetualAsset::mint(Origin::signed(CHARLIE), 50i128, 20i128));
-		assert_ok!(PerpetualAsset::mint(Origin::signed(GEORGES), -10i128, 20i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), DOT_MARKET, 100i128, 20i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(BOB), DOT_MARKET, -100i128, 20i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(CHARLIE), DOT_MARKET, 50i128, 20i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(GEORGES), DOT_MARKET, -10i128, 20i128));
 		PerpetualAsset::on_initialize(2);
 
 		MockPriceSource::set_price(Some(2u128.into()));
-		PerpetualAsset::update_margin();
-
-		assert_eq!(PerpetualAsset::inventory(&ALICE), 73i128);
-		assert_eq!(PerpetualAsset::inventory(&BOB), -100i128);
-		assert_eq!(PerpetualAsset::inventory(&CHARLIE), 36i128);
-		assert_eq!(PerpetualAsset::inventory(&GEORGES), -10i128);
-		assert_eq!(PerpetualAsset::balances(&ALICE), 100i128);
-		assert_eq!(PerpetualAsset::balances(&BOB), -100i128);
-		assert_eq!(PerpetualAsset::balances(&CHARLIE), 50i128);
-		assert_eq!(PerpetualAsset::balances(&GEORGES), -10i128);
-
+		PerpetualAsset::update_margin(DOT_MARKET);
+
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &ALICE), 73i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &BOB), -100i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &CHARLIE), 36i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &GEORGES), -10i128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &ALICE), 100i128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &BOB), -100i128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &CHARLIE), 50i128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &GEORGES), -10i128);
+
+		// BOB lost 80 more than his margin, nobody pays for it until he is settled
 		assert_eq!(PerpetualAsset::total_collateral_balance(), 80u128);
-		assert_eq!(PerpetualAsset::margin(&ALICE), 93u128);
-		assert_eq!(PerpetualAsset::margin(&BOB), 0u128);
-		assert_eq!(PerpetualAsset::margin(&CHARLIE), 56u128);
-		assert_eq!(PerpetualAsset::margin(&GEORGES), 10u128);
-
-		assert_ok!(PerpetualAsset::mint(Origin::signed(BOB), 0i128, 120i128));
-		assert_eq!(PerpetualAsset::margin(&BOB), 120u128);
+		assert_eq!(PerpetualAsset::margin(DOT_MARKET, &ALICE), 93u128);
+		assert_eq!(PerpetualAsset::margin(DOT_MARKET, &BOB), 0u128);
+		assert_eq!(PerpetualAsset::margin(DOT_MARKET, &CHARLIE), 57u128);
+		assert_eq!(PerpetualAsset::margin(DOT_MARKET, &GEORGES), 10u128);
+		assert_eq!(PerpetualAsset::total_margin(), 80u128);
+
+		// Then ALICE and CHARLIE pay for it pro-rata
+		assert_ok!(PerpetualAsset::mint(Origin::signed(BOB), DOT_MARKET, 0i128, 120i128));
+		assert_eq!(PerpetualAsset::margin(DOT_MARKET, &BOB), 120u128);
+		assert_eq!(PerpetualAsset::margin(DOT_MARKET, &ALICE), 40u128);
+		assert_eq!(PerpetualAsset::margin(DOT_MARKET, &CHARLIE), 30u128);
+		assert_eq!(PerpetualAsset::margin(DOT_MARKET, &GEORGES), 10u128);
 	});
 }
 
@@ -450,48 +642,64 @@ fn claim_collateral() {
 		System::set_block_number(1);
 		System::reset_events();
 		MockPriceSource::set_price(Some(20u128.into()));
-		PerpetualAsset::update_margin();
-
-		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), 100i128, 400i128));
-		assert_ok!(PerpetualAsset::mint(Origin::signed(BOB), -100i128, 400i128));
-		assert_ok!(PerpetualAsset::mint(Origin::signed(CHARLIE), 100i128, 400i128));
-		assert_ok!(PerpetualAsset::mint(Origin::signed(GEORGES), -100i128, 400i128));
-		PerpetualAsset::match_interest();
-
-		assert_eq!(PerpetualAsset::inventory(&ALICE), 100i128);
-		assert_eq!(PerpetualAsset::inventory(&BOB), -100i128);
-		assert_eq!(PerpetualAsset::inventory(&CHARLIE), 100i128);
-		assert_eq!(PerpetualAsset::inventory(&GEORGES), -100i128);
-		assert_eq!(PerpetualAsset::balances(&ALICE), 100i128);
-		assert_eq!(PerpetualAsset::balances(&BOB), -100i128);
-		assert_eq!(PerpetualAsset::balances(&CHARLIE), 100i128);
-		assert_eq!(PerpetualAsset::balances(&GEORGES), -100i128);
+		PerpetualAsset::update_margin(DOT_MARKET);
+
+		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), DOT_MARKET, 100i128, 400i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(BOB), DOT_MARKET, -100i128, 400i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(CHARLIE), DOT_MARKET, 100i128, 400i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(GEORGES), DOT_MARKET, -100i128, 400i128));
+		PerpetualAsset::match_interest(DOT_MARKET);
+
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &ALICE), 100i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &BOB), -100i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &CHARLIE), 100i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &GEORGES), -100i128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &ALICE), 100i128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &BOB), -100i128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &CHARLIE), 100i128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &GEORGES), -100i128);
 
 		// Price goes to 0, ALICE and CHARLIE should be fully liquidated
 		MockPriceSource::set_price(Some(0u128.into()));
-		PerpetualAsset::update_margin();
-		PerpetualAsset::liquidate();
+		PerpetualAsset::update_margin(DOT_MARKET);
+		PerpetualAsset::liquidate_accounts(DOT_MARKET);
 		assert_eq!(PerpetualAsset::total_collateral_balance(), 1600u128);
-		assert_eq!(PerpetualAsset::margin(&ALICE), 0u128);
-		assert_eq!(PerpetualAsset::margin(&BOB), 2400u128);
-		assert_eq!(PerpetualAsset::margin(&CHARLIE), 0u128);
-		assert_eq!(PerpetualAsset::margin(&GEORGES), 2400u128);
-
-		assert_eq!(PerpetualAsset::inventory(&ALICE), 0i128);
-		assert_eq!(PerpetualAsset::inventory(&BOB), -100i128);
-		assert_eq!(PerpetualAsset::inventory(&CHARLIE), 0i128);
-		assert_eq!(PerpetualAsset::inventory(&GEORGES), -100i128);
-		assert_eq!(PerpetualAsset::balances(&ALICE), 0i128);
-		assert_eq!(PerpetualAsset::balances(&BOB), -100i128);
-		assert_eq!(PerpetualAsset::balances(&CHARLIE), 0i128);
-		assert_eq!(PerpetualAsset::balances(&GEORGES), -100i128);
+		assert_eq!(PerpetualAsset::margin(DOT_MARKET, &ALICE), 0u128);
+		assert_eq!(PerpetualAsset::margin(DOT_MARKET, &BOB), 800u128);
+		assert_eq!(PerpetualAsset::margin(DOT_MARKET, &CHARLIE), 0u128);
+		assert_eq!(PerpetualAsset::margin(DOT_MARKET, &GEORGES), 800u128);
+
+		// Nobody is left to be short against
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &ALICE), 0i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &BOB), 0i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &CHARLIE), 0i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &GEORGES), 0i128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &ALICE), 0i128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &BOB), -100i128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &CHARLIE), 0i128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &GEORGES), -100i128);
+
+		// The pool loses 1000, it now only holds 600 for 1600 of margins
+		assert_ok!(<Tokens as MultiCurrency<_>>::withdraw(KUSD, &PerpetualAsset::account_id(), 1000u128));
+		assert_eq!(PerpetualAsset::total_margin(), 1600u128);
+		assert_eq!(PerpetualAsset::shortfall(), 1000u128);
 
 		// Claim back collateral
-		assert_ok!(PerpetualAsset::mint(Origin::signed(BOB), 0i128, -1600i128));
-		assert_noop!(
-			PerpetualAsset::mint(Origin::signed(GEORGES), 0i128, -1i128),
-			orml_tokens::Error::<Runtime>::BalanceTooLow,
+		assert_ok!(PerpetualAsset::mint(Origin::signed(BOB), DOT_MARKET, 0i128, -800i128));
+		assert_eq!(
+			last_event(),
+			Event::perpetualasset(crate::Event::BalanceUpdated(DOT_MARKET, BOB, -100i128))
 		);
+		assert_eq!(Tokens::total_balance(KUSD, &BOB), 999_999_999_999_999_900u128);
+		assert_eq!(PerpetualAsset::socialized_losses(DOT_MARKET, &BOB), 500u128);
+		assert_eq!(PerpetualAsset::margin(DOT_MARKET, &BOB), 0u128);
+		assert_eq!(PerpetualAsset::total_collateral_balance(), 300u128);
+
+		// GEORGES is not penalised for claiming last
+		assert_ok!(PerpetualAsset::mint(Origin::signed(GEORGES), DOT_MARKET, 0i128, -800i128));
+		assert_eq!(Tokens::total_balance(KUSD, &GEORGES), 999_999_999_999_999_900u128);
+		assert_eq!(PerpetualAsset::socialized_losses(DOT_MARKET, &GEORGES), 500u128);
+		assert_eq!(PerpetualAsset::total_collateral_balance(), 0u128);
 	});
 }
 
@@ -501,66 +709,1299 @@ fn claim_collateral_2() {
 		System::set_block_number(1);
 		System::reset_events();
 		MockPriceSource::set_price(Some(20u128.into()));
-		PerpetualAsset::update_margin();
-
-		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), 100i128, 400i128));
-		assert_ok!(PerpetualAsset::mint(Origin::signed(BOB), -100i128, 400i128));
-		assert_ok!(PerpetualAsset::mint(Origin::signed(CHARLIE), 100i128, 400i128));
-		assert_ok!(PerpetualAsset::mint(Origin::signed(GEORGES), -100i128, 400i128));
-		PerpetualAsset::match_interest();
-
-		assert_eq!(PerpetualAsset::inventory(&ALICE), 100i128);
-		assert_eq!(PerpetualAsset::inventory(&BOB), -100i128);
-		assert_eq!(PerpetualAsset::inventory(&CHARLIE), 100i128);
-		assert_eq!(PerpetualAsset::inventory(&GEORGES), -100i128);
-		assert_eq!(PerpetualAsset::balances(&ALICE), 100i128);
-		assert_eq!(PerpetualAsset::balances(&BOB), -100i128);
-		assert_eq!(PerpetualAsset::balances(&CHARLIE), 100i128);
-		assert_eq!(PerpetualAsset::balances(&GEORGES), -100i128);
+		PerpetualAsset::update_margin(DOT_MARKET);
+
+		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), DOT_MARKET, 100i128, 400i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(BOB), DOT_MARKET, -100i128, 400i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(CHARLIE), DOT_MARKET, 100i128, 400i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(GEORGES), DOT_MARKET, -100i128, 400i128));
+		PerpetualAsset::match_interest(DOT_MARKET);
+
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &ALICE), 100i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &BOB), -100i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &CHARLIE), 100i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &GEORGES), -100i128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &ALICE), 100i128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &BOB), -100i128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &CHARLIE), 100i128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &GEORGES), -100i128);
 
 		// Price goes to 0, ALICE and CHARLIE should be fully liquidated
 		MockPriceSource::set_price(Some(0u128.into()));
-		PerpetualAsset::update_margin();
-		PerpetualAsset::liquidate();
+		PerpetualAsset::update_margin(DOT_MARKET);
+		PerpetualAsset::liquidate_accounts(DOT_MARKET);
 		assert_eq!(PerpetualAsset::total_collateral_balance(), 1600u128);
-		assert_eq!(PerpetualAsset::margin(&ALICE), 0u128);
-		assert_eq!(PerpetualAsset::margin(&BOB), 2400u128);
-		assert_eq!(PerpetualAsset::margin(&CHARLIE), 0u128);
-		assert_eq!(PerpetualAsset::margin(&GEORGES), 2400u128);
-
-		assert_eq!(PerpetualAsset::inventory(&ALICE), 0i128);
-		assert_eq!(PerpetualAsset::inventory(&BOB), -100i128);
-		assert_eq!(PerpetualAsset::inventory(&CHARLIE), 0i128);
-		assert_eq!(PerpetualAsset::inventory(&GEORGES), -100i128);
-		assert_eq!(PerpetualAsset::balances(&ALICE), 0i128);
-		assert_eq!(PerpetualAsset::balances(&BOB), -100i128);
-		assert_eq!(PerpetualAsset::balances(&CHARLIE), 0i128);
-		assert_eq!(PerpetualAsset::balances(&GEORGES), -100i128);
+		assert_eq!(PerpetualAsset::margin(DOT_MARKET, &ALICE), 0u128);
+		assert_eq!(PerpetualAsset::margin(DOT_MARKET, &BOB), 800u128);
+		assert_eq!(PerpetualAsset::margin(DOT_MARKET, &CHARLIE), 0u128);
+		assert_eq!(PerpetualAsset::margin(DOT_MARKET, &GEORGES), 800u128);
+
+		// Nobody is left to be short against
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &ALICE), 0i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &BOB), 0i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &CHARLIE), 0i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &GEORGES), 0i128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &ALICE), 0i128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &BOB), -100i128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &CHARLIE), 0i128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &GEORGES), -100i128);
 
 		// Claim back collateral
-		assert_ok!(PerpetualAsset::mint(Origin::signed(BOB), 100i128, -1600i128));
-		PerpetualAsset::match_interest();
+		assert_ok!(PerpetualAsset::mint(Origin::signed(BOB), DOT_MARKET, 100i128, -800i128));
+		PerpetualAsset::match_interest(DOT_MARKET);
 		
 		MockPriceSource::set_price(Some(10u128.into()));
-		PerpetualAsset::update_margin();
-		PerpetualAsset::liquidate();
+		PerpetualAsset::update_margin(DOT_MARKET);
+		PerpetualAsset::liquidate_accounts(DOT_MARKET);
 
-		assert_eq!(PerpetualAsset::total_collateral_balance(), 0u128);
-		assert_eq!(PerpetualAsset::margin(&ALICE), 0u128);
-		assert_eq!(PerpetualAsset::margin(&BOB), 800u128);
-		assert_eq!(PerpetualAsset::margin(&CHARLIE), 0u128);
-		assert_eq!(PerpetualAsset::margin(&GEORGES), 2400u128);
-
-		assert_eq!(PerpetualAsset::inventory(&ALICE), 0i128);
-		assert_eq!(PerpetualAsset::inventory(&BOB), 0i128);
-		assert_eq!(PerpetualAsset::inventory(&CHARLIE), 0i128);
-		assert_eq!(PerpetualAsset::inventory(&GEORGES), 0i128);
-		assert_eq!(PerpetualAsset::balances(&ALICE), 0i128);
-		assert_eq!(PerpetualAsset::balances(&BOB), 0i128);
-		assert_eq!(PerpetualAsset::balances(&CHARLIE), 0i128);
-		assert_eq!(PerpetualAsset::balances(&GEORGES), -100i128);
-
-		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), 100i128, 200i128));
-		assert_ok!(PerpetualAsset::mint(Origin::signed(GEORGES), 0i128, -200i128));
-	});
-}
\ No newline at end of file
+		assert_eq!(PerpetualAsset::total_collateral_balance(), 800u128);
+		assert_eq!(PerpetualAsset::margin(DOT_MARKET, &ALICE), 0u128);
+		assert_eq!(PerpetualAsset::margin(DOT_MARKET, &BOB), 0u128);
+		assert_eq!(PerpetualAsset::margin(DOT_MARKET, &CHARLIE), 0u128);
+		assert_eq!(PerpetualAsset::margin(DOT_MARKET, &GEORGES), 800u128);
+
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &ALICE), 0i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &BOB), 0i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &CHARLIE), 0i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &GEORGES), 0i128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &ALICE), 0i128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &BOB), 0i128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &CHARLIE), 0i128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &GEORGES), -100i128);
+
+		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), DOT_MARKET, 100i128, 200i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(GEORGES), DOT_MARKET, 0i128, -200i128));
+	});
+}
+#[test]
+fn funding_works() {
+	ExtBuilder::default().build().execute_with(|| {
+		System::set_block_number(1);
+		System::reset_events();
+		MockPriceSource::set_price(Some(20u128.into()));
+		PerpetualAsset::update_margin(DOT_MARKET);
+
+		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), DOT_MARKET, 300i128, 1300i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(BOB), DOT_MARKET, -100i128, 500i128));
+		PerpetualAsset::match_interest(DOT_MARKET);
+
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &ALICE), 100i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &BOB), -100i128);
+		let alice_margin = PerpetualAsset::margin(DOT_MARKET, &ALICE);
+		let bob_margin = PerpetualAsset::margin(DOT_MARKET, &BOB);
+
+		// Not a funding block
+		PerpetualAsset::on_initialize(9);
+		assert_eq!(PerpetualAsset::cumulative_funding(DOT_MARKET), (FixedI128::zero(), FixedI128::zero()));
+		assert_eq!(PerpetualAsset::margin(DOT_MARKET, &ALICE), alice_margin);
+
+		// Longs are crowded, 300 vs 100, so they pay 20 * 1% * 1/2 = 0.1 per unit of inventory,
+		// that is 0.1 * 1/3 per unit of the interest ALICE had matched
+		PerpetualAsset::on_initialize(10);
+		assert_eq!(
+			PerpetualAsset::cumulative_funding(DOT_MARKET),
+			(FixedI128::saturating_from_rational(1, 30), FixedI128::saturating_from_rational(-1, 10))
+		);
+		assert_eq!(PerpetualAsset::margin(DOT_MARKET, &ALICE), alice_margin - 10);
+		assert_eq!(PerpetualAsset::margin(DOT_MARKET, &BOB), bob_margin + 10);
+		assert!(System::events().iter().any(|record|
+			record.event == Event::perpetualasset(crate::Event::FundingPaid(DOT_MARKET, ALICE, 10i128))));
+		assert!(System::events().iter().any(|record|
+			record.event == Event::perpetualasset(crate::Event::FundingPaid(DOT_MARKET, BOB, -10i128))));
+
+		// Already settled, nothing more to pay
+		PerpetualAsset::on_initialize(11);
+		assert_eq!(PerpetualAsset::margin(DOT_MARKET, &ALICE), alice_margin - 10);
+		assert_eq!(PerpetualAsset::margin(DOT_MARKET, &BOB), bob_margin + 10);
+	});
+}
+
+#[test]
+fn account_stats_and_position_info_work() {
+	ExtBuilder::default().build().execute_with(|| {
+		System::set_block_number(1);
+		System::reset_events();
+		PerpetualAsset::on_initialize(1);
+
+		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), BTC_MARKET, 1000i128, 203i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(BOB), BTC_MARKET, -1000i128, 1000i128));
+		PerpetualAsset::match_interest(BTC_MARKET);
+
+		MockPriceSource::set_price(Some(Price::saturating_from_rational(11, 10)));
+		PerpetualAsset::update_margin(BTC_MARKET);
+		assert_eq!(PerpetualAsset::position_info(BTC_MARKET, &ALICE), PositionInfo {
+			size: 1000,
+			inventory: 1000,
+			open_interest: 0,
+			entry_price: Some(Price::saturating_from_integer(1)),
+			margin: 300,
+			unrealized_pnl: 100,
+			margin_ratio: Some(Price::saturating_from_rational(300, 1100)),
+		});
+		assert_eq!(PerpetualAsset::position_info(BTC_MARKET, &BOB).unrealized_pnl, -100i128);
+		assert_eq!(PerpetualAsset::position_info(BTC_MARKET, &BOB).margin, 897u128);
+
+		// Touching the account realizes its PnL
+		assert_ok!(PerpetualAsset::withdraw_margin(Origin::signed(ALICE), BTC_MARKET, 50));
+		assert_eq!(PerpetualAsset::account_stats(BTC_MARKET, &ALICE), AccountStats {
+			deposited: 203,
+			withdrawn: 50,
+			realized_pnl: 100,
+			fees_paid: 3,
+			funding_paid: 0,
+		});
+		assert_eq!(PerpetualAsset::position_info(BTC_MARKET, &ALICE).unrealized_pnl, 0i128);
+		assert_eq!(PerpetualAsset::position_info(BTC_MARKET, &ALICE).margin, 250u128);
+		assert_eq!(PerpetualAsset::account_stats(BTC_MARKET, &BOB).realized_pnl, 0i128);
+
+		// Without a position
+		assert_eq!(PerpetualAsset::position_info(BTC_MARKET, &CHARLIE).margin_ratio, None);
+	});
+}
+
+#[test]
+fn entry_price_is_volume_weighted() {
+	ExtBuilder::default().build().execute_with(|| {
+		System::set_block_number(1);
+		System::reset_events();
+		PerpetualAsset::on_initialize(1);
+
+		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), DOT_MARKET, 100i128, 1000i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(BOB), DOT_MARKET, -100i128, 1000i128));
+		PerpetualAsset::match_interest(DOT_MARKET);
+		assert_eq!(PerpetualAsset::entry_price(DOT_MARKET, &ALICE), Some(1u128.into()));
+
+		// ALICE gets another 100 at 2
+		MockPriceSource::set_price(Some(2u128.into()));
+		PerpetualAsset::update_margin(DOT_MARKET);
+		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), DOT_MARKET, 100i128, 0i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(CHARLIE), DOT_MARKET, -100i128, 1000i128));
+		PerpetualAsset::match_interest(DOT_MARKET);
+		let average = Price::saturating_from_rational(3, 2);
+		assert!(System::events().iter().any(|r| r.event ==
+			Event::perpetualasset(crate::Event::InventoryFilled(DOT_MARKET, ALICE, 100i128, 2u128.into(), Some(average)))));
+		assert_eq!(PerpetualAsset::entry_price(DOT_MARKET, &ALICE), Some(average));
+		assert_eq!(PerpetualAsset::entry_price(DOT_MARKET, &BOB), Some(1u128.into()));
+		assert_eq!(PerpetualAsset::entry_price(DOT_MARKET, &CHARLIE), Some(2u128.into()));
+
+		// Reducing the inventory leaves the entry price as it is
+		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), DOT_MARKET, -100i128, 0i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(BOB), DOT_MARKET, 100i128, 0i128));
+		PerpetualAsset::match_interest(DOT_MARKET);
+		assert!(System::events().iter().any(|r| r.event ==
+			Event::perpetualasset(crate::Event::InventoryFilled(DOT_MARKET, ALICE, -100i128, 2u128.into(), Some(average)))));
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &ALICE), 100i128);
+		assert_eq!(PerpetualAsset::entry_price(DOT_MARKET, &ALICE), Some(average));
+		assert_eq!(PerpetualAsset::entry_price(DOT_MARKET, &BOB), None);
+		assert_eq!(PerpetualAsset::position_info(DOT_MARKET, &ALICE).entry_price, Some(average));
+	});
+}
+
+#[test]
+fn create_market_works() {
+	ExtBuilder::default().build().execute_with(|| {
+		System::set_block_number(1);
+		System::reset_events();
+		assert_eq!(PerpetualAsset::next_market_id(), 2);
+
+		assert_noop!(
+			PerpetualAsset::create_market(
+				Origin::signed(ALICE),
+				XBTC,
+				Permill::from_percent(50),
+				Permill::from_percent(25),
+				Permill::zero(),
+				Permill::zero(),
+				Permill::one(),
+				MatchingPolicy::ProRata,
+			),
+			sp_runtime::DispatchError::BadOrigin,
+		);
+
+		assert_ok!(PerpetualAsset::create_market(
+			Origin::root(),
+			XBTC,
+			Permill::from_percent(50),
+			Permill::from_percent(25),
+			Permill::zero(),
+			Permill::zero(),
+			Permill::one(),
+			MatchingPolicy::ProRata,
+		));
+		assert_eq!(
+			last_event(),
+			Event::perpetualasset(crate::Event::MarketCreated(2, XBTC))
+		);
+		assert_eq!(PerpetualAsset::next_market_id(), 3);
+		assert_eq!(PerpetualAsset::markets(2), Some(MarketInfo {
+			currency_id: XBTC,
+			initial_im_ratio: Permill::from_percent(50),
+			liquidation_ratio: Permill::from_percent(25),
+			transaction_fee: Permill::zero(),
+			liquidation_penalty: Permill::zero(),
+			liquidation_step: Permill::one(),
+			matching_policy: MatchingPolicy::ProRata,
+		}));
+
+		PerpetualAsset::on_initialize(1);
+		assert_noop!(
+			PerpetualAsset::mint(Origin::signed(ALICE), 2, 100i128, 49i128),
+			crate::Error::<Runtime>::NotEnoughIM
+		);
+		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), 2, 100i128, 50i128));
+		assert_noop!(
+			PerpetualAsset::mint(Origin::signed(ALICE), 3, 100i128, 50i128),
+			crate::Error::<Runtime>::MarketNotFound
+		);
+	});
+}
+
+#[test]
+fn markets_are_isolated() {
+	ExtBuilder::default().build().execute_with(|| {
+		System::set_block_number(1);
+		System::reset_events();
+		PerpetualAsset::on_initialize(1);
+
+		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), DOT_MARKET, 100i128, 20i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(BOB), DOT_MARKET, -100i128, 20i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), BTC_MARKET, -100i128, 21i128));
+
+		PerpetualAsset::on_initialize(2);
+
+		// The BTC market fee went to the treasury
+		assert_eq!(PerpetualAsset::total_collateral_balance(), 60u128);
+		assert_eq!(PerpetualAsset::margin(DOT_MARKET, &ALICE), 20u128);
+		assert_eq!(PerpetualAsset::margin(BTC_MARKET, &ALICE), 20u128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &ALICE), 100i128);
+		assert_eq!(PerpetualAsset::balances(BTC_MARKET, &ALICE), -100i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &ALICE), 100i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &BOB), -100i128);
+		// Nobody is long in the BTC market
+		assert_eq!(PerpetualAsset::inventory(BTC_MARKET, &ALICE), 0i128);
+		assert_eq!(PerpetualAsset::balances(BTC_MARKET, &BOB), 0i128);
+	});
+}
+
+#[test]
+fn update_market_parameters_works() {
+	ExtBuilder::default().build().execute_with(|| {
+		System::set_block_number(1);
+		System::reset_events();
+
+		assert_noop!(
+			PerpetualAsset::set_initial_im_ratio(Origin::signed(ALICE), DOT_MARKET, Permill::from_percent(50)),
+			sp_runtime::DispatchError::BadOrigin,
+		);
+		assert_noop!(
+			PerpetualAsset::set_initial_im_ratio(Origin::root(), 2, Permill::from_percent(50)),
+			crate::Error::<Runtime>::MarketNotFound,
+		);
+		// Liquidation ratio is 10%
+		assert_noop!(
+			PerpetualAsset::set_initial_im_ratio(Origin::root(), DOT_MARKET, Permill::from_percent(10)),
+			crate::Error::<Runtime>::InvalidLiquidationRatio,
+		);
+		assert_noop!(
+			PerpetualAsset::set_liquidation_ratio(Origin::root(), DOT_MARKET, Permill::from_percent(20)),
+			crate::Error::<Runtime>::InvalidLiquidationRatio,
+		);
+		assert_noop!(
+			PerpetualAsset::create_market(
+				Origin::root(),
+				XBTC,
+				Permill::from_percent(20),
+				Permill::from_percent(30),
+				Permill::zero(),
+				Permill::zero(),
+				Permill::one(),
+				MatchingPolicy::ProRata,
+			),
+			crate::Error::<Runtime>::InvalidLiquidationRatio,
+		);
+
+		assert_ok!(PerpetualAsset::set_initial_im_ratio(Origin::root(), DOT_MARKET, Permill::from_percent(50)));
+		assert_eq!(
+			last_event(),
+			Event::perpetualasset(crate::Event::InitialIMRatioUpdated(DOT_MARKET, Permill::from_percent(50)))
+		);
+		assert_ok!(PerpetualAsset::set_liquidation_ratio(Origin::root(), DOT_MARKET, Permill::from_percent(25)));
+		assert_eq!(
+			last_event(),
+			Event::perpetualasset(crate::Event::LiquidationRatioUpdated(DOT_MARKET, Permill::from_percent(25)))
+		);
+		assert_ok!(PerpetualAsset::set_transaction_fee(Origin::root(), DOT_MARKET, Permill::from_percent(1)));
+		assert_eq!(
+			last_event(),
+			Event::perpetualasset(crate::Event::TransactionFeeUpdated(DOT_MARKET, Permill::from_percent(1)))
+		);
+
+		let info = PerpetualAsset::markets(DOT_MARKET).unwrap();
+		assert_eq!(info.initial_im_ratio, Permill::from_percent(50));
+		assert_eq!(info.liquidation_ratio, Permill::from_percent(25));
+		assert_eq!(info.transaction_fee, Permill::from_percent(1));
+
+		assert_ok!(PerpetualAsset::set_liquidation_penalty(Origin::root(), DOT_MARKET, Permill::from_percent(5)));
+		assert_eq!(
+			last_event(),
+			Event::perpetualasset(crate::Event::LiquidationPenaltyUpdated(DOT_MARKET, Permill::from_percent(5)))
+		);
+		assert_eq!(PerpetualAsset::markets(DOT_MARKET).unwrap().liquidation_penalty, Permill::from_percent(5));
+
+		assert_ok!(PerpetualAsset::set_liquidation_step(Origin::root(), DOT_MARKET, Permill::from_percent(25)));
+		assert_eq!(
+			last_event(),
+			Event::perpetualasset(crate::Event::LiquidationStepUpdated(DOT_MARKET, Permill::from_percent(25)))
+		);
+		assert_eq!(PerpetualAsset::markets(DOT_MARKET).unwrap().liquidation_step, Permill::from_percent(25));
+
+		assert_ok!(PerpetualAsset::set_matching_policy(Origin::root(), DOT_MARKET, MatchingPolicy::Fifo));
+		assert_eq!(
+			last_event(),
+			Event::perpetualasset(crate::Event::MatchingPolicyUpdated(DOT_MARKET, MatchingPolicy::Fifo))
+		);
+		assert_eq!(PerpetualAsset::markets(DOT_MARKET).unwrap().matching_policy, MatchingPolicy::Fifo);
+
+		// New parameters apply to the next mint: 50 IM and 1 fee needed
+		PerpetualAsset::on_initialize(1);
+		assert_noop!(
+			PerpetualAsset::mint(Origin::signed(ALICE), DOT_MARKET, 100i128, 50i128),
+			crate::Error::<Runtime>::NotEnoughIM
+		);
+		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), DOT_MARKET, 100i128, 51i128));
+	});
+}
+
+#[test]
+fn fees_go_to_treasury() {
+	ExtBuilder::default().build().execute_with(|| {
+		System::set_block_number(1);
+		System::reset_events();
+		PerpetualAsset::on_initialize(1);
+
+		// 200 IM and 3 fee needed
+		assert_noop!(
+			PerpetualAsset::mint(Origin::signed(ALICE), BTC_MARKET, 1000i128, 202i128),
+			crate::Error::<Runtime>::NotEnoughIM
+		);
+		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), BTC_MARKET, 1000i128, 203i128));
+		assert!(System::events().iter().any(|record|
+			record.event == Event::perpetualasset(crate::Event::FeeCharged(BTC_MARKET, ALICE, 3u128))));
+		assert_eq!(PerpetualAsset::margin(BTC_MARKET, &ALICE), 200u128);
+		assert_eq!(PerpetualAsset::total_collateral_balance(), 200u128);
+		// Half of the fee, rounded down, is kept in the insurance fund
+		assert_eq!(Tokens::total_balance(KUSD, &TREASURY), 2u128);
+		assert_eq!(PerpetualAsset::insurance_fund_balance(), 1u128);
+		assert_eq!(PerpetualAsset::total_fees_collected(), 3u128);
+
+		// Closing without collateral still pays the fee out of the margin
+		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), BTC_MARKET, -1000i128, 0i128));
+		assert_eq!(PerpetualAsset::margin(BTC_MARKET, &ALICE), 197u128);
+		assert_eq!(PerpetualAsset::total_collateral_balance(), 197u128);
+		assert_eq!(Tokens::total_balance(KUSD, &TREASURY), 4u128);
+		assert_eq!(PerpetualAsset::insurance_fund_balance(), 2u128);
+		assert_eq!(PerpetualAsset::total_fees_collected(), 6u128);
+
+		// No fee in the DOT market
+		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), DOT_MARKET, 1000i128, 200i128));
+		assert_eq!(PerpetualAsset::total_fees_collected(), 6u128);
+	});
+}
+
+#[test]
+fn insurance_fund_covers_losses_first() {
+	ExtBuilder::default().build().execute_with(|| {
+		System::set_block_number(1);
+		System::reset_events();
+		MockPriceSource::set_price(Some(20u128.into()));
+		PerpetualAsset::update_margin(DOT_MARKET);
+
+		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), DOT_MARKET, 100i128, 400i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(BOB), DOT_MARKET, -100i128, 400i128));
+		PerpetualAsset::match_interest(DOT_MARKET);
+		assert_ok!(<Tokens as MultiCurrency<_>>::deposit(KUSD, &PerpetualAsset::insurance_fund_account(), 1000u128));
+
+		// ALICE loses 2000 with only 400 of margin, realized when she is checked for liquidation
+		MockPriceSource::set_price(Some(0u128.into()));
+		PerpetualAsset::update_margin(DOT_MARKET);
+		PerpetualAsset::liquidate_accounts(DOT_MARKET);
+
+		assert!(System::events().iter().any(|record|
+			record.event == Event::perpetualasset(crate::Event::InsuranceFundWithdrawn(DOT_MARKET, 1000u128))));
+		assert!(System::events().iter().any(|record|
+			record.event == Event::perpetualasset(crate::Event::LossesDeleveraged(DOT_MARKET, 600u128))));
+		assert_eq!(PerpetualAsset::insurance_fund_balance(), 0u128);
+		assert_eq!(PerpetualAsset::margin(DOT_MARKET, &ALICE), 0u128);
+		assert_eq!(PerpetualAsset::margin(DOT_MARKET, &BOB), 1800u128);
+		assert_eq!(PerpetualAsset::total_collateral_balance(), 1800u128);
+		assert_eq!(PerpetualAsset::shortfall(), 0u128);
+	});
+}
+
+#[test]
+fn insurance_fund_covers_all_losses() {
+	ExtBuilder::default().build().execute_with(|| {
+		System::set_block_number(1);
+		System::reset_events();
+		MockPriceSource::set_price(Some(20u128.into()));
+		PerpetualAsset::update_margin(DOT_MARKET);
+
+		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), DOT_MARKET, 100i128, 400i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(BOB), DOT_MARKET, -100i128, 400i128));
+		PerpetualAsset::match_interest(DOT_MARKET);
+		assert_ok!(<Tokens as MultiCurrency<_>>::deposit(KUSD, &PerpetualAsset::insurance_fund_account(), 5000u128));
+
+		MockPriceSource::set_price(Some(0u128.into()));
+		PerpetualAsset::update_margin(DOT_MARKET);
+		PerpetualAsset::liquidate_accounts(DOT_MARKET);
+
+		assert!(System::events().iter().any(|record|
+			record.event == Event::perpetualasset(crate::Event::InsuranceFundWithdrawn(DOT_MARKET, 1600u128))));
+		assert!(!System::events().iter().any(|record|
+			matches!(record.event, Event::perpetualasset(crate::Event::LossesDeleveraged(..)))));
+		assert_eq!(PerpetualAsset::insurance_fund_balance(), 3400u128);
+		assert_eq!(PerpetualAsset::margin(DOT_MARKET, &BOB), 2400u128);
+		assert_eq!(PerpetualAsset::total_collateral_balance(), 2400u128);
+	});
+}
+
+#[test]
+fn liquidator_is_rewarded() {
+	ExtBuilder::default().build().execute_with(|| {
+		System::set_block_number(1);
+		System::reset_events();
+		MockPriceSource::set_price(Some(20u128.into()));
+		PerpetualAsset::update_margin(DOT_MARKET);
+
+		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), DOT_MARKET, 100i128, 400i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(BOB), DOT_MARKET, -100i128, 1000i128));
+		PerpetualAsset::match_interest(DOT_MARKET);
+
+		assert_noop!(
+			PerpetualAsset::liquidate(Origin::signed(CHARLIE), DOT_MARKET, ALICE),
+			crate::Error::<Runtime>::NotLiquidatable
+		);
+
+		// ALICE has 100 of margin left, and needs 170 to keep her inventory
+		MockPriceSource::set_price(Some(17u128.into()));
+		PerpetualAsset::update_margin(DOT_MARKET);
+		assert_eq!(PerpetualAsset::margin(DOT_MARKET, &ALICE), 100u128);
+
+		assert_ok!(PerpetualAsset::liquidate(Origin::signed(CHARLIE), DOT_MARKET, ALICE));
+		assert_eq!(
+			last_event(),
+			Event::perpetualasset(crate::Event::Liquidated(DOT_MARKET, ALICE, 10u128))
+		);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &ALICE), 0i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &ALICE), 0i128);
+		assert_eq!(PerpetualAsset::margin(DOT_MARKET, &ALICE), 90u128);
+		assert_eq!(PerpetualAsset::account_stats(DOT_MARKET, &ALICE).realized_pnl, -300i128);
+		assert_eq!(PerpetualAsset::account_stats(DOT_MARKET, &ALICE).fees_paid, 10u128);
+		assert_eq!(Tokens::total_balance(KUSD, &CHARLIE), 1_000_000_000_000_000_005u128);
+		assert_eq!(PerpetualAsset::insurance_fund_balance(), 5u128);
+		assert_eq!(PerpetualAsset::total_collateral_balance(), 1390u128);
+
+		assert_noop!(
+			PerpetualAsset::liquidate(Origin::signed(CHARLIE), DOT_MARKET, BOB),
+			crate::Error::<Runtime>::NotLiquidatable
+		);
+	});
+}
+
+#[test]
+fn liquidating_an_empty_account_fails() {
+	ExtBuilder::default().build().execute_with(|| {
+		System::set_block_number(1);
+		System::reset_events();
+		PerpetualAsset::on_initialize(1);
+
+		assert_noop!(
+			PerpetualAsset::liquidate(Origin::signed(BOB), DOT_MARKET, CHARLIE),
+			crate::Error::<Runtime>::NotLiquidatable
+		);
+		assert_noop!(
+			PerpetualAsset::liquidate_unsigned(Origin::none(), DOT_MARKET, CHARLIE),
+			crate::Error::<Runtime>::NotLiquidatable
+		);
+	});
+}
+
+#[test]
+fn partial_liquidation_works() {
+	ExtBuilder::default().build().execute_with(|| {
+		System::set_block_number(1);
+		System::reset_events();
+		assert_ok!(PerpetualAsset::set_liquidation_step(Origin::root(), DOT_MARKET, Permill::from_percent(25)));
+		MockPriceSource::set_price(Some(20u128.into()));
+		PerpetualAsset::update_margin(DOT_MARKET);
+
+		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), DOT_MARKET, 100i128, 400i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(BOB), DOT_MARKET, -100i128, 1000i128));
+		PerpetualAsset::match_interest(DOT_MARKET);
+
+		// ALICE has 100 of margin left, enough to keep 50 of her inventory (85 < 100)
+		MockPriceSource::set_price(Some(17u128.into()));
+		PerpetualAsset::update_margin(DOT_MARKET);
+		assert_ok!(PerpetualAsset::liquidate(Origin::signed(CHARLIE), DOT_MARKET, ALICE));
+		assert!(System::events().iter().any(|r| r.event ==
+			Event::perpetualasset(crate::Event::PositionLiquidated(DOT_MARKET, ALICE, 50i128, 50i128))));
+		assert_eq!(
+			last_event(),
+			Event::perpetualasset(crate::Event::Liquidated(DOT_MARKET, ALICE, 5u128))
+		);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &ALICE), 50i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &ALICE), 50i128);
+		assert_eq!(PerpetualAsset::margin(DOT_MARKET, &ALICE), 95u128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &BOB), -50i128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &BOB), -100i128);
+		assert_ok!(PerpetualAsset::do_try_state());
+		assert_eq!(Tokens::total_balance(KUSD, &CHARLIE), 1_000_000_000_000_000_002u128);
+		assert_eq!(PerpetualAsset::insurance_fund_balance(), 3u128);
+
+		// What is left is safe
+		assert_noop!(
+			PerpetualAsset::liquidate(Origin::signed(CHARLIE), DOT_MARKET, ALICE),
+			crate::Error::<Runtime>::NotLiquidatable
+		);
+	});
+}
+
+#[test]
+fn partial_liquidation_with_the_smallest_step_works() {
+	ExtBuilder::default().build().execute_with(|| {
+		System::set_block_number(1);
+		System::reset_events();
+		assert_ok!(PerpetualAsset::set_liquidation_step(Origin::root(), DOT_MARKET, Permill::from_parts(1)));
+		MockPriceSource::set_price(Some(20u128.into()));
+		PerpetualAsset::update_margin(DOT_MARKET);
+
+		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), DOT_MARKET, 100i128, 400i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(BOB), DOT_MARKET, -100i128, 1000i128));
+		PerpetualAsset::match_interest(DOT_MARKET);
+
+		// Steps of 1, ALICE keeps 58 of her inventory (98.6 < 100 <= 100.3)
+		MockPriceSource::set_price(Some(17u128.into()));
+		PerpetualAsset::update_margin(DOT_MARKET);
+		assert_ok!(PerpetualAsset::liquidate(Origin::signed(CHARLIE), DOT_MARKET, ALICE));
+		assert!(System::events().iter().any(|r| r.event ==
+			Event::perpetualasset(crate::Event::PositionLiquidated(DOT_MARKET, ALICE, 42i128, 58i128))));
+		assert_eq!(
+			last_event(),
+			Event::perpetualasset(crate::Event::Liquidated(DOT_MARKET, ALICE, 4u128))
+		);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &ALICE), 58i128);
+		assert_eq!(PerpetualAsset::margin(DOT_MARKET, &ALICE), 96u128);
+		assert_ok!(PerpetualAsset::do_try_state());
+	});
+}
+
+#[test]
+fn liquidation_without_step_closes_everything() {
+	ExtBuilder::default().build().execute_with(|| {
+		System::set_block_number(1);
+		System::reset_events();
+		assert_ok!(PerpetualAsset::set_liquidation_step(Origin::root(), DOT_MARKET, Permill::zero()));
+		MockPriceSource::set_price(Some(20u128.into()));
+		PerpetualAsset::update_margin(DOT_MARKET);
+
+		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), DOT_MARKET, 100i128, 400i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(BOB), DOT_MARKET, -100i128, 1000i128));
+		PerpetualAsset::match_interest(DOT_MARKET);
+
+		// 50 would be enough, but without a step the whole inventory goes
+		MockPriceSource::set_price(Some(17u128.into()));
+		PerpetualAsset::update_margin(DOT_MARKET);
+		PerpetualAsset::liquidate_accounts(DOT_MARKET);
+		assert!(System::events().iter().any(|r| r.event ==
+			Event::perpetualasset(crate::Event::PositionLiquidated(DOT_MARKET, ALICE, 100i128, 0i128))));
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &ALICE), 0i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &BOB), 0i128);
+		assert_ok!(PerpetualAsset::do_try_state());
+
+		// Open interest with neither inventory nor margin is no reason to divide by a zero step
+		MinimumMargin::set(0);
+		MockPriceSource::set_price(Some(0u128.into()));
+		PerpetualAsset::update_margin(DOT_MARKET);
+		assert_ok!(PerpetualAsset::mint(Origin::signed(CHARLIE), DOT_MARKET, 10i128, 0i128));
+		PerpetualAsset::liquidate_accounts(DOT_MARKET);
+		assert_noop!(
+			PerpetualAsset::liquidate(Origin::signed(GEORGES), DOT_MARKET, CHARLIE),
+			crate::Error::<Runtime>::NotLiquidatable
+		);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &CHARLIE), 10i128);
+	});
+}
+
+#[test]
+fn liquidated_inventory_goes_to_open_interest_first() {
+	ExtBuilder::default().build().execute_with(|| {
+		System::set_block_number(1);
+		System::reset_events();
+		// Pro-rata, the inventories follow the totals, only priority policies rebalance
+		assert_ok!(PerpetualAsset::set_matching_policy(Origin::root(), DOT_MARKET, MatchingPolicy::Fifo));
+		MockPriceSource::set_price(Some(20u128.into()));
+		PerpetualAsset::update_margin(DOT_MARKET);
+
+		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), DOT_MARKET, 100i128, 400i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(BOB), DOT_MARKET, -100i128, 1000i128));
+		PerpetualAsset::match_interest(DOT_MARKET);
+		assert_ok!(PerpetualAsset::do_try_state());
+
+		// CHARLIE and GEORGES come in after the match, their interest is still open
+		assert_ok!(PerpetualAsset::mint(Origin::signed(CHARLIE), DOT_MARKET, 60i128, 1000i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(GEORGES), DOT_MARKET, 20i128, 1000i128));
+
+		MockPriceSource::set_price(Some(17u128.into()));
+		PerpetualAsset::update_margin(DOT_MARKET);
+		PerpetualAsset::liquidate_accounts(DOT_MARKET);
+
+		// 80 taken over by CHARLIE and GEORGES, the last 20 removed from BOB
+		assert!(System::events().iter().any(|r| r.event ==
+			Event::perpetualasset(crate::Event::InventoryRebalanced(DOT_MARKET, 80u128, 20u128))));
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &ALICE), 0i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &CHARLIE), 60i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &GEORGES), 20i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &BOB), -80i128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &BOB), -100i128);
+		assert_ok!(PerpetualAsset::do_try_state());
+	});
+}
+
+#[test]
+fn liquidation_penalty_goes_to_insurance_fund() {
+	ExtBuilder::default().build().execute_with(|| {
+		System::set_block_number(1);
+		System::reset_events();
+		MockPriceSource::set_price(Some(20u128.into()));
+		PerpetualAsset::update_margin(DOT_MARKET);
+
+		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), DOT_MARKET, 100i128, 400i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(BOB), DOT_MARKET, -100i128, 1000i128));
+		PerpetualAsset::match_interest(DOT_MARKET);
+
+		MockPriceSource::set_price(Some(17u128.into()));
+		PerpetualAsset::update_margin(DOT_MARKET);
+		PerpetualAsset::liquidate_accounts(DOT_MARKET);
+
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &ALICE), 0i128);
+		assert_eq!(PerpetualAsset::margin(DOT_MARKET, &ALICE), 90u128);
+		assert_eq!(PerpetualAsset::insurance_fund_balance(), 10u128);
+		assert_eq!(PerpetualAsset::margin(DOT_MARKET, &BOB), 1300u128);
+	});
+}
+
+#[test]
+fn order_book_works() {
+	ExtBuilder::default().build().execute_with(|| {
+		System::set_block_number(1);
+		System::reset_events();
+		MockPriceSource::set_price(Some(20u128.into()));
+		PerpetualAsset::update_margin(DOT_MARKET);
+		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), DOT_MARKET, 0i128, 400i128));
+
+		assert_noop!(
+			PerpetualAsset::place_order(Origin::signed(ALICE), DOT_MARKET, Side::Long, 0, 19u128.into(), 10),
+			crate::Error::<Runtime>::InvalidOrder
+		);
+		assert_noop!(
+			PerpetualAsset::place_order(Origin::signed(ALICE), DOT_MARKET, Side::Long, 100, 19u128.into(), 0),
+			crate::Error::<Runtime>::InvalidOrder
+		);
+		assert_noop!(
+			PerpetualAsset::place_order(Origin::signed(ALICE), 5, Side::Long, 100, 19u128.into(), 10),
+			crate::Error::<Runtime>::MarketNotFound
+		);
+
+		assert_ok!(PerpetualAsset::place_order(Origin::signed(ALICE), DOT_MARKET, Side::Long, 100, 19u128.into(), 10));
+		assert_eq!(
+			last_event(),
+			Event::perpetualasset(crate::Event::OrderPlaced(DOT_MARKET, 0, ALICE, Side::Long, 100, 19u128.into()))
+		);
+		assert_ok!(PerpetualAsset::place_order(Origin::signed(BOB), DOT_MARKET, Side::Short, 100, 21u128.into(), 10));
+		assert_eq!(Tokens::accounts(BOB, KUSD).reserved, 10);
+
+		// Price is 20, neither limit is reached
+		PerpetualAsset::match_interest(DOT_MARKET);
+		assert!(PerpetualAsset::orders(DOT_MARKET, 0).is_some());
+		assert!(PerpetualAsset::orders(DOT_MARKET, 1).is_some());
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &ALICE), 0i128);
+
+		assert_noop!(
+			PerpetualAsset::cancel_order(Origin::signed(BOB), DOT_MARKET, 0),
+			crate::Error::<Runtime>::NotOrderOwner
+		);
+		assert_noop!(
+			PerpetualAsset::cancel_order(Origin::signed(BOB), DOT_MARKET, 2),
+			crate::Error::<Runtime>::OrderNotFound
+		);
+
+		// Price goes to 18, ALICE's long is filled
+		MockPriceSource::set_price(Some(18u128.into()));
+		PerpetualAsset::update_margin(DOT_MARKET);
+		PerpetualAsset::match_interest(DOT_MARKET);
+		assert!(System::events().iter().any(|r| r.event ==
+			Event::perpetualasset(crate::Event::OrderFilled(DOT_MARKET, 0, ALICE))));
+		assert_eq!(PerpetualAsset::orders(DOT_MARKET, 0), None);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &ALICE), 100i128);
+		// The order deposit is back, the position deposit taken
+		assert_eq!(Tokens::accounts(ALICE, KUSD).reserved, 5);
+		assert!(PerpetualAsset::orders(DOT_MARKET, 1).is_some());
+
+		assert_ok!(PerpetualAsset::cancel_order(Origin::signed(BOB), DOT_MARKET, 1));
+		assert_eq!(
+			last_event(),
+			Event::perpetualasset(crate::Event::OrderCancelled(DOT_MARKET, 1, BOB))
+		);
+		assert_eq!(PerpetualAsset::orders(DOT_MARKET, 1), None);
+		assert_eq!(Tokens::accounts(BOB, KUSD).reserved, 0);
+		assert_eq!(PerpetualAsset::total_orders(DOT_MARKET), 0);
+	});
+}
+
+#[test]
+fn order_book_is_bounded() {
+	ExtBuilder::default().build().execute_with(|| {
+		System::set_block_number(1);
+		System::reset_events();
+		MockPriceSource::set_price(Some(20u128.into()));
+		PerpetualAsset::update_margin(DOT_MARKET);
+		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), DOT_MARKET, 0i128, 400i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(BOB), DOT_MARKET, 0i128, 400i128));
+
+		assert_ok!(PerpetualAsset::place_order(Origin::signed(ALICE), DOT_MARKET, Side::Long, 10, 21u128.into(), 10));
+		assert_ok!(PerpetualAsset::place_order(Origin::signed(ALICE), DOT_MARKET, Side::Long, 10, 21u128.into(), 10));
+		assert_noop!(
+			PerpetualAsset::place_order(Origin::signed(ALICE), DOT_MARKET, Side::Long, 10, 21u128.into(), 10),
+			crate::Error::<Runtime>::TooManyOrders
+		);
+		assert_eq!(Tokens::accounts(ALICE, KUSD).reserved, 20);
+		assert_ok!(PerpetualAsset::place_order(Origin::signed(BOB), DOT_MARKET, Side::Long, 10, 21u128.into(), 10));
+		assert_ok!(PerpetualAsset::place_order(Origin::signed(BOB), DOT_MARKET, Side::Long, 10, 21u128.into(), 10));
+		assert_noop!(
+			PerpetualAsset::place_order(Origin::signed(CHARLIE), DOT_MARKET, Side::Long, 10, 21u128.into(), 10),
+			crate::Error::<Runtime>::OrderBookFull
+		);
+
+		// Only the 2 oldest orders are filled in a block
+		PerpetualAsset::match_interest(DOT_MARKET);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &ALICE), 20i128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &BOB), 0i128);
+		assert_eq!(Tokens::accounts(ALICE, KUSD).reserved, 5);
+		assert_eq!(PerpetualAsset::open_orders(DOT_MARKET, &ALICE), 0);
+		assert_eq!(PerpetualAsset::total_orders(DOT_MARKET), 2);
+
+		System::set_block_number(2);
+		PerpetualAsset::match_interest(DOT_MARKET);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &BOB), 20i128);
+		assert_eq!(Tokens::accounts(BOB, KUSD).reserved, 5);
+		assert_eq!(PerpetualAsset::total_orders(DOT_MARKET), 0);
+	});
+}
+
+#[test]
+fn expired_and_unfunded_orders_are_dropped() {
+	ExtBuilder::default().build().execute_with(|| {
+		System::set_block_number(1);
+		System::reset_events();
+		MockPriceSource::set_price(Some(20u128.into()));
+		PerpetualAsset::update_margin(DOT_MARKET);
+		assert_ok!(PerpetualAsset::mint(Origin::signed(GEORGES), DOT_MARKET, 0i128, 400i128));
+
+		// CHARLIE has no margin to back his order
+		assert_ok!(PerpetualAsset::place_order(Origin::signed(CHARLIE), DOT_MARKET, Side::Long, 100, 25u128.into(), 5));
+		assert_ok!(PerpetualAsset::place_order(Origin::signed(GEORGES), DOT_MARKET, Side::Short, 100, 25u128.into(), 1));
+
+		System::set_block_number(2);
+		PerpetualAsset::match_interest(DOT_MARKET);
+		assert!(System::events().iter().any(|r| r.event ==
+			Event::perpetualasset(crate::Event::OrderCancelled(DOT_MARKET, 0, CHARLIE))));
+		assert!(System::events().iter().any(|r| r.event ==
+			Event::perpetualasset(crate::Event::OrderCancelled(DOT_MARKET, 1, GEORGES))));
+		assert_eq!(PerpetualAsset::orders(DOT_MARKET, 0), None);
+		assert_eq!(PerpetualAsset::orders(DOT_MARKET, 1), None);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &CHARLIE), 0i128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &GEORGES), 0i128);
+	});
+}
+
+#[test]
+fn positions_are_bounded() {
+	ExtBuilder::default().build().execute_with(|| {
+		System::set_block_number(1);
+		System::reset_events();
+		PerpetualAsset::on_initialize(1);
+		MaxPositions::set(2);
+
+		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), DOT_MARKET, 100i128, 20i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(BOB), DOT_MARKET, -100i128, 20i128));
+		assert_eq!(PerpetualAsset::positions(DOT_MARKET), 2);
+		assert_noop!(
+			PerpetualAsset::mint(Origin::signed(CHARLIE), DOT_MARKET, 50i128, 20i128),
+			crate::Error::<Runtime>::TooManyPositions
+		);
+
+		// Switching side does not take another position
+		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), DOT_MARKET, -200i128, 20i128));
+		assert_eq!(PerpetualAsset::interest_queue(DOT_MARKET), vec![BOB, ALICE]);
+
+		// A position closed leaves room for another
+		assert_ok!(PerpetualAsset::mint(Origin::signed(BOB), DOT_MARKET, 100i128, 0i128));
+		assert_eq!(PerpetualAsset::interest_queue(DOT_MARKET), vec![ALICE]);
+		assert_ok!(PerpetualAsset::mint(Origin::signed(CHARLIE), DOT_MARKET, 50i128, 20i128));
+		assert_eq!(PerpetualAsset::interest_queue(DOT_MARKET), vec![ALICE, CHARLIE]);
+		assert_eq!(PerpetualAsset::positions(DOT_MARKET), 2);
+	});
+}
+
+#[test]
+fn positions_take_a_deposit() {
+	ExtBuilder::default().build().execute_with(|| {
+		System::set_block_number(1);
+		System::reset_events();
+		PerpetualAsset::on_initialize(1);
+		MaxPositions::set(2);
+		assert_ok!(Tokens::transfer(Origin::signed(ALICE), 6, KUSD, 5));
+
+		// A dust position takes a slot only for the deposit, 6 has 4 left once its margin is in
+		assert_noop!(
+			PerpetualAsset::mint(Origin::signed(6), DOT_MARKET, 1i128, 1i128),
+			orml_tokens::Error::<Runtime>::BalanceTooLow
+		);
+		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), DOT_MARKET, 1i128, 1i128));
+		assert_eq!(PerpetualAsset::position_deposit(DOT_MARKET, &ALICE), 5);
+		assert_eq!(Tokens::accounts(ALICE, KUSD).reserved, 5);
+
+		// Adding to the position or switching side takes nothing more
+		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), DOT_MARKET, 1i128, 1i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), DOT_MARKET, -3i128, 1i128));
+		assert_eq!(Tokens::accounts(ALICE, KUSD).reserved, 5);
+
+		// Closing gives it back
+		assert_ok!(PerpetualAsset::close_position(Origin::signed(ALICE), DOT_MARKET));
+		assert_eq!(PerpetualAsset::position_deposit(DOT_MARKET, &ALICE), 0);
+		assert_eq!(Tokens::accounts(ALICE, KUSD).reserved, 0);
+	});
+}
+
+#[test]
+fn fifo_matching_works() {
+	ExtBuilder::default().build().execute_with(|| {
+		System::set_block_number(1);
+		System::reset_events();
+		assert_ok!(PerpetualAsset::set_matching_policy(Origin::root(), DOT_MARKET, MatchingPolicy::Fifo));
+		MockPriceSource::set_price(Some(20u128.into()));
+		PerpetualAsset::update_margin(DOT_MARKET);
+
+		assert_ok!(PerpetualAsset::mint(Origin::signed(CHARLIE), DOT_MARKET, 50i128, 400i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), DOT_MARKET, 100i128, 400i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(BOB), DOT_MARKET, -120i128, 1000i128));
+		assert_eq!(PerpetualAsset::interest_queue(DOT_MARKET), vec![CHARLIE, ALICE, BOB]);
+
+		// CHARLIE came first and is filled fully, ALICE gets what is left
+		PerpetualAsset::match_interest(DOT_MARKET);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &CHARLIE), 50i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &ALICE), 70i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &BOB), -120i128);
+
+		// CHARLIE closes and reopens, he goes to the back of the queue
+		assert_ok!(PerpetualAsset::mint(Origin::signed(CHARLIE), DOT_MARKET, -50i128, 0i128));
+		assert_eq!(PerpetualAsset::interest_queue(DOT_MARKET), vec![ALICE, BOB]);
+		assert_ok!(PerpetualAsset::mint(Origin::signed(CHARLIE), DOT_MARKET, 50i128, 0i128));
+		assert_eq!(PerpetualAsset::interest_queue(DOT_MARKET), vec![ALICE, BOB, CHARLIE]);
+
+		PerpetualAsset::match_interest(DOT_MARKET);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &ALICE), 100i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &CHARLIE), 20i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &BOB), -120i128);
+	});
+}
+
+#[test]
+fn size_weighted_matching_works() {
+	ExtBuilder::default().build().execute_with(|| {
+		System::set_block_number(1);
+		System::reset_events();
+		assert_ok!(PerpetualAsset::set_matching_policy(Origin::root(), DOT_MARKET, MatchingPolicy::SizeWeighted));
+		MockPriceSource::set_price(Some(20u128.into()));
+		PerpetualAsset::update_margin(DOT_MARKET);
+
+		assert_ok!(PerpetualAsset::mint(Origin::signed(CHARLIE), DOT_MARKET, -50i128, 400i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(GEORGES), DOT_MARKET, -100i128, 400i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(BOB), DOT_MARKET, -50i128, 400i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), DOT_MARKET, 130i128, 1000i128));
+
+		// GEORGES is the largest, then CHARLIE came before BOB
+		PerpetualAsset::match_interest(DOT_MARKET);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &GEORGES), -100i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &CHARLIE), -30i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &BOB), 0i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &ALICE), 130i128);
+		assert_ok!(PerpetualAsset::do_try_state());
+	});
+}
+
+/// Small linear congruential generator, good enough to draw test cases
+struct Lcg(u64);
+
+impl Lcg {
+	fn next(&mut self, max: u128) -> u128 {
+		self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+		(self.0 >> 33) as u128 % max
+	}
+}
+
+#[test]
+fn pro_rata_is_exact() {
+	let mut rng = Lcg(42);
+	for _ in 0..500 {
+		let n = 1 + rng.next(20) as usize;
+		let weights: Vec<(u64, Balance)> = (0..n).map(|i| (i as u64, rng.next(1_000_000))).collect();
+		let sum: Balance = weights.iter().map(|(_, w)| w).sum();
+		let total = rng.next(sum + 1);
+		let shares = PerpetualAsset::pro_rata(total, &weights);
+
+		assert_eq!(shares.iter().sum::<Balance>(), total);
+		for ((_, w), share) in weights.iter().zip(shares.iter()) {
+			assert!(share <= w);
+			let floor = if sum == 0 { 0 } else { w * total / sum };
+			assert!(*share == floor || *share == floor + 1);
+		}
+	}
+
+	// Equal remainders go to the smallest key
+	assert_eq!(PerpetualAsset::pro_rata(1, &[(2u64, 1u128), (1u64, 1u128)]), vec![0, 1]);
+	assert_eq!(PerpetualAsset::pro_rata(2, &[(3u64, 1u128), (1u64, 1u128), (2u64, 1u128)]), vec![0, 1, 1]);
+}
+
+#[test]
+fn pro_rata_matching_is_balanced() {
+	ExtBuilder::default().build().execute_with(|| {
+		let mut rng = Lcg(7);
+		for _ in 0..100 {
+			let n = 2 + rng.next(20);
+			for account in 100..100 + n {
+				let balance = rng.next(2_000) as Amount - 1_000;
+				PerpetualAsset::set_balance(DOT_MARKET, &account, balance);
+			}
+			PerpetualAsset::match_interest(DOT_MARKET);
+
+			assert_ok!(PerpetualAsset::do_try_state());
+			for account in 100..100 + n {
+				let balance = PerpetualAsset::balances(DOT_MARKET, &account);
+				let inventory = PerpetualAsset::inventory(DOT_MARKET, &account);
+				assert!(inventory.abs() <= balance.abs());
+				assert!(inventory == 0 || (inventory > 0) == (balance > 0));
+			}
+			for account in 100..100 + n {
+				PerpetualAsset::set_balance(DOT_MARKET, &account, 0);
+			}
+		}
+	});
+}
+
+#[test]
+fn matching_only_runs_when_interest_changes() {
+	ExtBuilder::default().build().execute_with(|| {
+		System::set_block_number(1);
+		System::reset_events();
+		MockPriceSource::set_price(Some(20u128.into()));
+		PerpetualAsset::update_margin(DOT_MARKET);
+
+		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), DOT_MARKET, 100i128, 400i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(BOB), DOT_MARKET, -100i128, 1000i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(CHARLIE), DOT_MARKET, -50i128, 1000i128));
+		assert_eq!(PerpetualAsset::total_interest(DOT_MARKET), (100u128, 150u128));
+		assert!(InterestChanged::<Runtime>::get(DOT_MARKET));
+
+		PerpetualAsset::match_interest(DOT_MARKET);
+		assert!(!InterestChanged::<Runtime>::get(DOT_MARKET));
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &BOB), -66i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &CHARLIE), -33i128);
+
+		// Nothing changed, the inventory is left as it is
+		PerpetualAsset::write_inventory(DOT_MARKET, &BOB, -90i128);
+		PerpetualAsset::match_interest(DOT_MARKET);
+		assert_eq!(Inventory::<Runtime>::get(DOT_MARKET, &BOB), -90i128);
+		PerpetualAsset::write_inventory(DOT_MARKET, &BOB, -100i128);
+
+		// Totals follow every change of interest
+		assert_ok!(PerpetualAsset::mint(Origin::signed(CHARLIE), DOT_MARKET, 100i128, 0i128));
+		assert_eq!(PerpetualAsset::total_interest(DOT_MARKET), (150u128, 100u128));
+		PerpetualAsset::match_interest(DOT_MARKET);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &BOB), -100i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &ALICE), 66i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &CHARLIE), 33i128);
+		assert_ok!(PerpetualAsset::do_try_state());
+	});
+}
+
+#[test]
+fn pro_rata_matching_only_writes_the_accounts_that_changed() {
+	ExtBuilder::default().build().execute_with(|| {
+		System::set_block_number(1);
+		System::reset_events();
+		MockPriceSource::set_price(Some(20u128.into()));
+		PerpetualAsset::update_margin(DOT_MARKET);
+
+		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), DOT_MARKET, 100i128, 400i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(BOB), DOT_MARKET, -100i128, 1000i128));
+		PerpetualAsset::match_interest(DOT_MARKET);
+
+		// Only CHARLIE is matched, BOB's inventory follows the totals
+		assert_ok!(PerpetualAsset::mint(Origin::signed(CHARLIE), DOT_MARKET, -100i128, 1000i128));
+		assert_eq!(ChangedInterest::<Runtime>::iter_prefix(DOT_MARKET).count(), 1);
+		System::reset_events();
+		PerpetualAsset::match_interest(DOT_MARKET);
+		assert_eq!(ChangedInterest::<Runtime>::iter_prefix(DOT_MARKET).count(), 0);
+		let filled: Vec<_> = System::events().into_iter().filter_map(|r| match r.event {
+			Event::perpetualasset(crate::Event::InventoryFilled(_, who, ..)) => Some(who),
+			_ => None,
+		}).collect();
+		assert_eq!(filled, vec![CHARLIE]);
+		assert_eq!(Inventory::<Runtime>::get(DOT_MARKET, &BOB), -100i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &ALICE), 100i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &BOB), -50i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &CHARLIE), -50i128);
+
+		// PnL is shared as per the inventories
+		MockPriceSource::set_price(Some(22u128.into()));
+		PerpetualAsset::update_margin(DOT_MARKET);
+		assert_eq!(PerpetualAsset::margin(DOT_MARKET, &ALICE), 600u128);
+		assert_eq!(PerpetualAsset::margin(DOT_MARKET, &BOB), 900u128);
+		assert_eq!(PerpetualAsset::margin(DOT_MARKET, &CHARLIE), 900u128);
+		assert_ok!(PerpetualAsset::do_try_state());
+	});
+}
+
+#[test]
+fn liquidation_sweep_is_bounded() {
+	ExtBuilder::default().build().execute_with(|| {
+		System::set_block_number(1);
+		System::reset_events();
+		MockPriceSource::set_price(Some(20u128.into()));
+		PerpetualAsset::update_margin(DOT_MARKET);
+
+		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), DOT_MARKET, 100i128, 400i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(CHARLIE), DOT_MARKET, 100i128, 400i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(GEORGES), DOT_MARKET, 100i128, 400i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(BOB), DOT_MARKET, -300i128, 5000i128));
+		PerpetualAsset::match_interest(DOT_MARKET);
+
+		// All the longs should be liquidated, but only 2 accounts are checked per block
+		MaxLiquidationsPerBlock::set(2);
+		MockPriceSource::set_price(Some(17u128.into()));
+		PerpetualAsset::update_margin(DOT_MARKET);
+		PerpetualAsset::liquidate_accounts(DOT_MARKET);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &ALICE), 0i128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &CHARLIE), 0i128);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &GEORGES), 100i128);
+		assert_eq!(PerpetualAsset::liquidation_cursor(DOT_MARKET), Some(GEORGES));
+
+		// The sweep carries on from the cursor, and starts over once at the end
+		PerpetualAsset::liquidate_accounts(DOT_MARKET);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &GEORGES), 0i128);
+		assert_eq!(PerpetualAsset::inventory(DOT_MARKET, &BOB), 0i128);
+		assert_eq!(PerpetualAsset::liquidation_cursor(DOT_MARKET), None);
+		assert_ok!(PerpetualAsset::do_try_state());
+	});
+}
+
+#[test]
+fn accounts_close_to_liquidation_are_checked_first() {
+	ExtBuilder::default().build().execute_with(|| {
+		System::set_block_number(1);
+		System::reset_events();
+		MockPriceSource::set_price(Some(20u128.into()));
+		PerpetualAsset::update_margin(DOT_MARKET);
+
+		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), DOT_MARKET, 100i128, 400i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(BOB), DOT_MARKET, -100i128, 5000i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(CHARLIE), DOT_MARKET, 10i128, 1000i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(GEORGES), DOT_MARKET, -10i128, 1000i128));
+		PerpetualAsset::match_interest(DOT_MARKET);
+
+		// ALICE has 300 of margin left, less than twice the 190 she needs
+		MaxLiquidationsPerBlock::set(2);
+		MockPriceSource::set_price(Some(19u128.into()));
+		PerpetualAsset::update_margin(DOT_MARKET);
+		PerpetualAsset::liquidate_accounts(DOT_MARKET);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &ALICE), 100i128);
+		assert_eq!(PerpetualAsset::liquidation_watchlist(DOT_MARKET), vec![ALICE]);
+		assert_eq!(PerpetualAsset::liquidation_cursor(DOT_MARKET), Some(CHARLIE));
+
+		// ALICE is checked again before the sweep gets back to her
+		MockPriceSource::set_price(Some(17u128.into()));
+		PerpetualAsset::update_margin(DOT_MARKET);
+		PerpetualAsset::liquidate_accounts(DOT_MARKET);
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &ALICE), 0i128);
+		assert_eq!(PerpetualAsset::liquidation_watchlist(DOT_MARKET), vec![]);
+		assert_eq!(PerpetualAsset::liquidation_cursor(DOT_MARKET), Some(GEORGES));
+	});
+}
+
+#[test]
+fn liquidation_cursor_follows_the_queue() {
+	ExtBuilder::default().build().execute_with(|| {
+		System::set_block_number(1);
+		System::reset_events();
+		MockPriceSource::set_price(Some(20u128.into()));
+		PerpetualAsset::update_margin(DOT_MARKET);
+
+		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), DOT_MARKET, 100i128, 400i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(BOB), DOT_MARKET, -100i128, 5000i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(CHARLIE), DOT_MARKET, 10i128, 1000i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(GEORGES), DOT_MARKET, -10i128, 1000i128));
+		PerpetualAsset::match_interest(DOT_MARKET);
+
+		MaxLiquidationsPerBlock::set(2);
+		PerpetualAsset::liquidate_accounts(DOT_MARKET);
+		assert_eq!(PerpetualAsset::liquidation_cursor(DOT_MARKET), Some(CHARLIE));
+
+		// The sweep does not start over when the account under the cursor leaves the queue
+		assert_ok!(PerpetualAsset::close_position(Origin::signed(CHARLIE), DOT_MARKET));
+		assert_eq!(PerpetualAsset::liquidation_cursor(DOT_MARKET), Some(GEORGES));
+		assert_ok!(PerpetualAsset::close_position(Origin::signed(GEORGES), DOT_MARKET));
+		assert_eq!(PerpetualAsset::liquidation_cursor(DOT_MARKET), None);
+	});
+}
+
+#[test]
+fn offchain_worker_submits_liquidations() {
+	let (offchain, _) = TestOffchainExt::new();
+	let (pool, pool_state) = TestTransactionPoolExt::new();
+	let mut t = ExtBuilder::default().build();
+	t.register_extension(OffchainWorkerExt::new(offchain.clone()));
+	t.register_extension(OffchainDbExt::new(offchain));
+	t.register_extension(TransactionPoolExt::new(pool));
+
+	t.execute_with(|| {
+		System::set_block_number(1);
+		MockPriceSource::set_price(Some(20u128.into()));
+		PerpetualAsset::update_margin(DOT_MARKET);
+
+		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), DOT_MARKET, 100i128, 400i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(CHARLIE), DOT_MARKET, 100i128, 450i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(BOB), DOT_MARKET, -200i128, 5000i128));
+		PerpetualAsset::match_interest(DOT_MARKET);
+
+		// ALICE has 100 of margin left and CHARLIE 150, both need 170
+		MockPriceSource::set_price(Some(17u128.into()));
+		PerpetualAsset::update_margin(DOT_MARKET);
+		PerpetualAsset::offchain_worker(1);
+
+		let calls: Vec<_> = pool_state.write().transactions.drain(..).map(|tx| {
+			let tx = TestXt::<mock::Call, ()>::decode(&mut &*tx).unwrap();
+			assert_eq!(tx.signature, None);
+			tx.call
+		}).collect();
+		let alice = Call::<Runtime>::liquidate_unsigned(DOT_MARKET, ALICE);
+		let charlie = Call::<Runtime>::liquidate_unsigned(DOT_MARKET, CHARLIE);
+		assert_eq!(calls, vec![alice.clone().into(), charlie.clone().into()]);
+
+		// Nothing is submitted again within the same interval
+		PerpetualAsset::offchain_worker(1);
+		assert!(pool_state.read().transactions.is_empty());
+
+		// The most under-water account goes first
+		let priority = |call: &Call<Runtime>| <PerpetualAsset as ValidateUnsigned>::validate_unsigned(
+			TransactionSource::External, call).unwrap().priority;
+		assert!(priority(&alice) > priority(&charlie));
+
+		assert_ok!(PerpetualAsset::liquidate_unsigned(Origin::none(), DOT_MARKET, ALICE));
+		assert_eq!(PerpetualAsset::balances(DOT_MARKET, &ALICE), 0i128);
+		assert!(<PerpetualAsset as ValidateUnsigned>::validate_unsigned(
+			TransactionSource::External, &alice).is_err());
+		assert_noop!(
+			PerpetualAsset::liquidate_unsigned(Origin::none(), DOT_MARKET, ALICE),
+			Error::<Runtime>::NotLiquidatable
+		);
+	});
+}
+
+#[test]
+fn position_queries_work() {
+	ExtBuilder::default().build().execute_with(|| {
+		System::set_block_number(1);
+		System::reset_events();
+		PerpetualAsset::on_initialize(1);
+
+		MockPriceSource::set_price(Some(20u128.into()));
+		PerpetualAsset::update_margin(DOT_MARKET);
+		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), DOT_MARKET, 100i128, 400i128));
+		assert_ok!(PerpetualAsset::mint(Origin::signed(BOB), DOT_MARKET, -100i128, 1000i128));
+		PerpetualAsset::match_interest(DOT_MARKET);
+
+		assert_eq!(PerpetualAsset::margin_ratio(DOT_MARKET, &ALICE), Some(Price::saturating_from_rational(1, 5)));
+		// 0.1 * P * 100 = 400 + (P - 20) * 100
+		assert_eq!(PerpetualAsset::liquidation_price(DOT_MARKET, &ALICE), Some(Price::saturating_from_rational(160, 9)));
+		// 0.1 * P * 100 = 1000 - (P - 20) * 100
+		assert_eq!(PerpetualAsset::liquidation_price(DOT_MARKET, &BOB), Some(Price::saturating_from_rational(300, 11)));
+		assert_eq!(PerpetualAsset::liquidation_price(DOT_MARKET, &CHARLIE), None);
+
+		assert_eq!(PerpetualAsset::required_margin(DOT_MARKET, &ALICE, 10i128), Some(440u128));
+		assert_eq!(PerpetualAsset::max_mintable(DOT_MARKET, &ALICE, 0i128), Some(0u128));
+		assert_eq!(PerpetualAsset::max_mintable(DOT_MARKET, &ALICE, 100i128), Some(25u128));
+		assert_noop!(
+			PerpetualAsset::mint(Origin::signed(ALICE), DOT_MARKET, 26i128, 100i128),
+			crate::Error::<Runtime>::NotEnoughIM
+		);
+		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), DOT_MARKET, 25i128, 100i128));
+	});
+}
+
+#[test]
+fn amounts_are_serialized_as_strings() {
+	let info = PositionInfo {
+		size: -2_000_000_000_000_000_000,
+		inventory: -1_000_000_000_000_000_001,
+		open_interest: -999_999_999_999_999_999,
+		entry_price: None,
+		margin: 300,
+		unrealized_pnl: 0,
+		margin_ratio: None,
+	};
+	let json = serde_json::to_value(&info).unwrap();
+	assert_eq!(json["size"], "-2000000000000000000");
+	assert_eq!(json["inventory"], "-1000000000000000001");
+	assert_eq!(json["margin"], "300");
+	assert_eq!(serde_json::from_value::<PositionInfo>(json).unwrap(), info);
+}
+
+#[test]
+fn simulate_mint_works() {
+	ExtBuilder::default().build().execute_with(|| {
+		System::set_block_number(1);
+		System::reset_events();
+		PerpetualAsset::on_initialize(1);
+
+		// The fee of 3 comes out of the margin before the IM check
+		assert_eq!(PerpetualAsset::simulate_mint(BTC_MARKET, &ALICE, 1000i128, 200i128), MintSimulation {
+			balance: 1000,
+			margin: 197,
+			fee: 3,
+			required_im: 200,
+			error: Some(crate::Error::<Runtime>::NotEnoughIM.into()),
+		});
+		assert_eq!(PerpetualAsset::simulate_mint(BTC_MARKET, &ALICE, 1000i128, 203i128), MintSimulation {
+			balance: 1000,
+			margin: 200,
+			fee: 3,
+			required_im: 200,
+			error: None,
+		});
+		assert_eq!(
+			PerpetualAsset::simulate_mint(BTC_MARKET, &ALICE, 1000i128, 2_000_000_000_000_000_000i128).error,
+			Some(orml_tokens::Error::<Runtime>::BalanceTooLow.into())
+		);
+		assert_eq!(PerpetualAsset::simulate_mint(2, &ALICE, 1000i128, 203i128).error,
+			Some(crate::Error::<Runtime>::MarketNotFound.into()));
+
+		// Nothing changed
+		assert_eq!(PerpetualAsset::balances(BTC_MARKET, &ALICE), 0i128);
+		assert_eq!(PerpetualAsset::margin(BTC_MARKET, &ALICE), 0u128);
+		assert_eq!(PerpetualAsset::account_stats(BTC_MARKET, &ALICE), AccountStats::default());
+
+		assert_ok!(PerpetualAsset::mint(Origin::signed(ALICE), BTC_MARKET, 1000i128, 203i128));
+		assert_eq!(PerpetualAsset::margin(BTC_MARKET, &ALICE), 200u128);
+	});
+}
@@ -0,0 +1,246 @@
+// Copyright (C) 2021 Georges Dib.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Weights for module_perpetualasset
+//!
+//! PLACEHOLDER WEIGHTS: none of these figures comes from a benchmark run. They are rough estimates,
+//! not to be relied upon in production, until the benchmarks in `benchmarking.rs` are run on the
+//! reference hardware of the runtime, which overwrites this file with:
+//!
+//! ./target/release/node benchmark --chain=dev --steps=50 --repeat=20
+//! --pallet=module_perpetualasset --extrinsic=* --execution=wasm --wasm-execution=compiled
+//! --heap-pages=4096 --output=./src/weights.rs
+
+#![allow(unused_parens)]
+#![allow(unused_imports)]
+#![allow(clippy::unnecessary_cast)]
+
+use frame_support::{traits::Get, weights::{Weight, constants::RocksDbWeight}};
+use sp_std::marker::PhantomData;
+
+/// Weight functions needed for module_perpetualasset.
+pub trait WeightInfo {
+	fn create_market() -> Weight;
+	fn set_market_parameter() -> Weight;
+	fn mint_or_burn(n: u32) -> Weight;
+	fn deposit_margin() -> Weight;
+	fn withdraw_margin(n: u32) -> Weight;
+	fn close_position(n: u32) -> Weight;
+	fn place_order() -> Weight;
+	fn cancel_order() -> Weight;
+	fn liquidate(n: u32) -> Weight;
+	fn update_margin() -> Weight;
+	fn accrue_funding() -> Weight;
+	fn liquidate_accounts(n: u32) -> Weight;
+	fn fill_orders(n: u32) -> Weight;
+	fn match_interest(n: u32) -> Weight;
+	fn rebalance_inventory(n: u32) -> Weight;
+}
+
+/// Placeholder weights for module_perpetualasset, to be replaced by benchmarked ones.
+pub struct SubstrateWeight<T>(PhantomData<T>);
+impl<T: frame_system::Config> WeightInfo for SubstrateWeight<T> {
+	fn create_market() -> Weight {
+		(21_304_000 as Weight)
+			.saturating_add(T::DbWeight::get().reads(1 as Weight))
+			.saturating_add(T::DbWeight::get().writes(2 as Weight))
+	}
+	fn set_market_parameter() -> Weight {
+		(19_870_000 as Weight)
+			.saturating_add(T::DbWeight::get().reads(1 as Weight))
+			.saturating_add(T::DbWeight::get().writes(2 as Weight))
+	}
+	fn mint_or_burn(n: u32, ) -> Weight {
+		(142_551_000 as Weight)
+			.saturating_add((1_204_000 as Weight).saturating_mul(n as Weight))
+			.saturating_add(T::DbWeight::get().reads(16 as Weight))
+			.saturating_add(T::DbWeight::get().writes(13 as Weight))
+	}
+	fn deposit_margin() -> Weight {
+		(58_203_000 as Weight)
+			.saturating_add(T::DbWeight::get().reads(8 as Weight))
+			.saturating_add(T::DbWeight::get().writes(5 as Weight))
+	}
+	fn withdraw_margin(n: u32, ) -> Weight {
+		(71_948_000 as Weight)
+			.saturating_add((1_107_000 as Weight).saturating_mul(n as Weight))
+			.saturating_add(T::DbWeight::get().reads(12 as Weight))
+			.saturating_add(T::DbWeight::get().writes(6 as Weight))
+	}
+	fn close_position(n: u32, ) -> Weight {
+		(163_075_000 as Weight)
+			.saturating_add((38_216_000 as Weight).saturating_mul(n as Weight))
+			.saturating_add(T::DbWeight::get().reads(21 as Weight))
+			.saturating_add(T::DbWeight::get().reads((4 as Weight).saturating_mul(n as Weight)))
+			.saturating_add(T::DbWeight::get().writes(16 as Weight))
+			.saturating_add(T::DbWeight::get().writes((4 as Weight).saturating_mul(n as Weight)))
+	}
+	fn place_order() -> Weight {
+		(48_375_000 as Weight)
+			.saturating_add(T::DbWeight::get().reads(5 as Weight))
+			.saturating_add(T::DbWeight::get().writes(5 as Weight))
+	}
+	fn cancel_order() -> Weight {
+		(41_538_000 as Weight)
+			.saturating_add(T::DbWeight::get().reads(4 as Weight))
+			.saturating_add(T::DbWeight::get().writes(4 as Weight))
+	}
+	fn liquidate(n: u32, ) -> Weight {
+		(187_320_000 as Weight)
+			.saturating_add((38_216_000 as Weight).saturating_mul(n as Weight))
+			.saturating_add(T::DbWeight::get().reads(22 as Weight))
+			.saturating_add(T::DbWeight::get().reads((4 as Weight).saturating_mul(n as Weight)))
+			.saturating_add(T::DbWeight::get().writes(17 as Weight))
+			.saturating_add(T::DbWeight::get().writes((4 as Weight).saturating_mul(n as Weight)))
+	}
+	fn update_margin() -> Weight {
+		(23_146_000 as Weight)
+			.saturating_add(T::DbWeight::get().reads(4 as Weight))
+			.saturating_add(T::DbWeight::get().writes(2 as Weight))
+	}
+	fn accrue_funding() -> Weight {
+		(14_912_000 as Weight)
+			.saturating_add(T::DbWeight::get().reads(3 as Weight))
+			.saturating_add(T::DbWeight::get().writes(1 as Weight))
+	}
+	fn liquidate_accounts(n: u32, ) -> Weight {
+		(24_180_000 as Weight)
+			.saturating_add((104_562_000 as Weight).saturating_mul(n as Weight))
+			.saturating_add(T::DbWeight::get().reads(5 as Weight))
+			.saturating_add(T::DbWeight::get().reads((11 as Weight).saturating_mul(n as Weight)))
+			.saturating_add(T::DbWeight::get().writes(2 as Weight))
+			.saturating_add(T::DbWeight::get().writes((8 as Weight).saturating_mul(n as Weight)))
+	}
+	fn fill_orders(n: u32, ) -> Weight {
+		(11_207_000 as Weight)
+			.saturating_add((133_815_000 as Weight).saturating_mul(n as Weight))
+			.saturating_add(T::DbWeight::get().reads(2 as Weight))
+			.saturating_add(T::DbWeight::get().reads((14 as Weight).saturating_mul(n as Weight)))
+			.saturating_add(T::DbWeight::get().writes((13 as Weight).saturating_mul(n as Weight)))
+	}
+	fn match_interest(n: u32, ) -> Weight {
+		(29_641_000 as Weight)
+			.saturating_add((40_733_000 as Weight).saturating_mul(n as Weight))
+			.saturating_add(T::DbWeight::get().reads(4 as Weight))
+			.saturating_add(T::DbWeight::get().reads((7 as Weight).saturating_mul(n as Weight)))
+			.saturating_add(T::DbWeight::get().writes(1 as Weight))
+			.saturating_add(T::DbWeight::get().writes((5 as Weight).saturating_mul(n as Weight)))
+	}
+	fn rebalance_inventory(n: u32, ) -> Weight {
+		(17_954_000 as Weight)
+			.saturating_add((38_216_000 as Weight).saturating_mul(n as Weight))
+			.saturating_add(T::DbWeight::get().reads(1 as Weight))
+			.saturating_add(T::DbWeight::get().reads((4 as Weight).saturating_mul(n as Weight)))
+			.saturating_add(T::DbWeight::get().writes((4 as Weight).saturating_mul(n as Weight)))
+	}
+}
+
+// For backwards compatibility and tests
+impl WeightInfo for () {
+	fn create_market() -> Weight {
+		(21_304_000 as Weight)
+			.saturating_add(RocksDbWeight::get().reads(1 as Weight))
+			.saturating_add(RocksDbWeight::get().writes(2 as Weight))
+	}
+	fn set_market_parameter() -> Weight {
+		(19_870_000 as Weight)
+			.saturating_add(RocksDbWeight::get().reads(1 as Weight))
+			.saturating_add(RocksDbWeight::get().writes(2 as Weight))
+	}
+	fn mint_or_burn(n: u32, ) -> Weight {
+		(142_551_000 as Weight)
+			.saturating_add((1_204_000 as Weight).saturating_mul(n as Weight))
+			.saturating_add(RocksDbWeight::get().reads(16 as Weight))
+			.saturating_add(RocksDbWeight::get().writes(13 as Weight))
+	}
+	fn deposit_margin() -> Weight {
+		(58_203_000 as Weight)
+			.saturating_add(RocksDbWeight::get().reads(8 as Weight))
+			.saturating_add(RocksDbWeight::get().writes(5 as Weight))
+	}
+	fn withdraw_margin(n: u32, ) -> Weight {
+		(71_948_000 as Weight)
+			.saturating_add((1_107_000 as Weight).saturating_mul(n as Weight))
+			.saturating_add(RocksDbWeight::get().reads(12 as Weight))
+			.saturating_add(RocksDbWeight::get().writes(6 as Weight))
+	}
+	fn close_position(n: u32, ) -> Weight {
+		(163_075_000 as Weight)
+			.saturating_add((38_216_000 as Weight).saturating_mul(n as Weight))
+			.saturating_add(RocksDbWeight::get().reads(21 as Weight))
+			.saturating_add(RocksDbWeight::get().reads((4 as Weight).saturating_mul(n as Weight)))
+			.saturating_add(RocksDbWeight::get().writes(16 as Weight))
+			.saturating_add(RocksDbWeight::get().writes((4 as Weight).saturating_mul(n as Weight)))
+	}
+	fn place_order() -> Weight {
+		(48_375_000 as Weight)
+			.saturating_add(RocksDbWeight::get().reads(5 as Weight))
+			.saturating_add(RocksDbWeight::get().writes(5 as Weight))
+	}
+	fn cancel_order() -> Weight {
+		(41_538_000 as Weight)
+			.saturating_add(RocksDbWeight::get().reads(4 as Weight))
+			.saturating_add(RocksDbWeight::get().writes(4 as Weight))
+	}
+	fn liquidate(n: u32, ) -> Weight {
+		(187_320_000 as Weight)
+			.saturating_add((38_216_000 as Weight).saturating_mul(n as Weight))
+			.saturating_add(RocksDbWeight::get().reads(22 as Weight))
+			.saturating_add(RocksDbWeight::get().reads((4 as Weight).saturating_mul(n as Weight)))
+			.saturating_add(RocksDbWeight::get().writes(17 as Weight))
+			.saturating_add(RocksDbWeight::get().writes((4 as Weight).saturating_mul(n as Weight)))
+	}
+	fn update_margin() -> Weight {
+		(23_146_000 as Weight)
+			.saturating_add(RocksDbWeight::get().reads(4 as Weight))
+			.saturating_add(RocksDbWeight::get().writes(2 as Weight))
+	}
+	fn accrue_funding() -> Weight {
+		(14_912_000 as Weight)
+			.saturating_add(RocksDbWeight::get().reads(3 as Weight))
+			.saturating_add(RocksDbWeight::get().writes(1 as Weight))
+	}
+	fn liquidate_accounts(n: u32, ) -> Weight {
+		(24_180_000 as Weight)
+			.saturating_add((104_562_000 as Weight).saturating_mul(n as Weight))
+			.saturating_add(RocksDbWeight::get().reads(5 as Weight))
+			.saturating_add(RocksDbWeight::get().reads((11 as Weight).saturating_mul(n as Weight)))
+			.saturating_add(RocksDbWeight::get().writes(2 as Weight))
+			.saturating_add(RocksDbWeight::get().writes((8 as Weight).saturating_mul(n as Weight)))
+	}
+	fn fill_orders(n: u32, ) -> Weight {
+		(11_207_000 as Weight)
+			.saturating_add((133_815_000 as Weight).saturating_mul(n as Weight))
+			.saturating_add(RocksDbWeight::get().reads(2 as Weight))
+			.saturating_add(RocksDbWeight::get().reads((14 as Weight).saturating_mul(n as Weight)))
+			.saturating_add(RocksDbWeight::get().writes((13 as Weight).saturating_mul(n as Weight)))
+	}
+	fn match_interest(n: u32, ) -> Weight {
+		(29_641_000 as Weight)
+			.saturating_add((40_733_000 as Weight).saturating_mul(n as Weight))
+			.saturating_add(RocksDbWeight::get().reads(4 as Weight))
+			.saturating_add(RocksDbWeight::get().reads((7 as Weight).saturating_mul(n as Weight)))
+			.saturating_add(RocksDbWeight::get().writes(1 as Weight))
+			.saturating_add(RocksDbWeight::get().writes((5 as Weight).saturating_mul(n as Weight)))
+	}
+	fn rebalance_inventory(n: u32, ) -> Weight {
+		(17_954_000 as Weight)
+			.saturating_add((38_216_000 as Weight).saturating_mul(n as Weight))
+			.saturating_add(RocksDbWeight::get().reads(1 as Weight))
+			.saturating_add(RocksDbWeight::get().reads((4 as Weight).saturating_mul(n as Weight)))
+			.saturating_add(RocksDbWeight::get().writes((4 as Weight).saturating_mul(n as Weight)))
+	}
+}